@@ -0,0 +1,107 @@
+use serde::{Deserialize, Serialize};
+use tree_sitter::{Node, Parser};
+
+/// A module imported by a Python file. Files that can't be parsed, including most Python 2
+/// code, get a single item with the error instead.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ImportItem {
+    pub path: String,
+    pub imported_module: Option<String>,
+    pub parse_error: Option<String>,
+}
+
+pub struct ImportParser {
+    parser: Parser,
+}
+
+impl ImportParser {
+    pub fn new() -> Self {
+        let mut parser = Parser::new();
+        parser
+            .set_language(&tree_sitter_python::LANGUAGE.into())
+            .expect("Incompatible tree-sitter-python version");
+        ImportParser { parser }
+    }
+
+    pub fn parse(&mut self, path: &str, source: &[u8]) -> Vec<ImportItem> {
+        match self.imported_modules(source) {
+            Ok(modules) => modules
+                .into_iter()
+                .map(|module| ImportItem {
+                    path: path.to_string(),
+                    imported_module: Some(module),
+                    parse_error: None,
+                })
+                .collect(),
+            Err(e) => vec![ImportItem {
+                path: path.to_string(),
+                imported_module: None,
+                parse_error: Some(e),
+            }],
+        }
+    }
+
+    /// Modules imported anywhere in the file, in the order they appear. Relative imports keep
+    /// their leading dots.
+    fn imported_modules(&mut self, source: &[u8]) -> Result<Vec<String>, String> {
+        let tree = self
+            .parser
+            .parse(source, None)
+            .ok_or_else(|| "Parsing was cancelled".to_string())?;
+        let root = tree.root_node();
+        if root.has_error() {
+            return Err(describe_error(root));
+        }
+
+        let mut modules = vec![];
+        let mut cursor = root.walk();
+        let mut stack = vec![root];
+        while let Some(node) = stack.pop() {
+            match node.kind() {
+                "import_statement" => {
+                    for name in node.children_by_field_name("name", &mut cursor) {
+                        let name = match name.kind() {
+                            "aliased_import" => name.child_by_field_name("name").unwrap_or(name),
+                            _ => name,
+                        };
+                        modules.push(node_text(name, source));
+                    }
+                }
+                "import_from_statement" => {
+                    if let Some(module) = node.child_by_field_name("module_name") {
+                        modules.push(node_text(module, source));
+                    }
+                }
+                "future_import_statement" => modules.push("__future__".to_string()),
+                _ => {
+                    let children: Vec<_> = node.named_children(&mut cursor).collect();
+                    stack.extend(children.into_iter().rev());
+                }
+            }
+        }
+        Ok(modules)
+    }
+}
+
+// Dotted names can contain whitespace between their parts.
+fn node_text(node: Node, source: &[u8]) -> String {
+    node.utf8_text(source)
+        .unwrap_or_default()
+        .split_whitespace()
+        .collect()
+}
+
+fn describe_error(root: Node) -> String {
+    let mut cursor = root.walk();
+    let mut stack = vec![root];
+    while let Some(node) = stack.pop() {
+        if node.is_error() || node.is_missing() {
+            return format!("Syntax error at line {}", node.start_position().row + 1);
+        }
+        if node.has_error() {
+            let children: Vec<_> = node.children(&mut cursor).collect();
+            stack.extend(children.into_iter().rev());
+        }
+    }
+    "Syntax error".to_string()
+}
@@ -0,0 +1,187 @@
+pub mod imports;
+pub mod rules;
+pub mod secrets;
+
+use std::path::Path;
+
+use itertools::Itertools;
+use polars::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::analysis::imports::{ImportItem, ImportParser};
+use crate::analysis::rules::Finding;
+use crate::analysis::secrets::{scan_secrets, SecretFinding};
+use crate::data::{companion_path, StreamingParquetWriter};
+use crate::repository::package::RepositoryPackage;
+
+/// Results of the analysis passes run over the files accepted from a package.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct PackageAnalysis {
+    pub imports: Vec<ImportItem>,
+    pub findings: Vec<Finding>,
+    pub secrets: Vec<SecretFinding>,
+}
+
+pub struct Analyzer {
+    imports: ImportParser,
+    analysis: PackageAnalysis,
+}
+
+impl Analyzer {
+    pub fn new() -> Self {
+        Analyzer {
+            imports: ImportParser::new(),
+            analysis: PackageAnalysis::default(),
+        }
+    }
+
+    /// Analyses an accepted file, returning whether it contains secrets.
+    pub fn add(&mut self, path: &str, data: &[u8]) -> bool {
+        if path.ends_with(".py") {
+            self.analysis.imports.extend(self.imports.parse(path, data));
+        }
+        self.analysis
+            .findings
+            .extend(rules::rules().scan(path, data));
+        let secrets = scan_secrets(path, data);
+        let has_secrets = !secrets.is_empty();
+        self.analysis.secrets.extend(secrets);
+        has_secrets
+    }
+
+    pub fn finish(self) -> PackageAnalysis {
+        self.analysis
+    }
+}
+
+/// Writes the analysis datasets next to the file index.
+pub struct AnalysisWriter {
+    imports: StreamingParquetWriter,
+    findings: StreamingParquetWriter,
+    secrets: StreamingParquetWriter,
+}
+
+impl AnalysisWriter {
+    pub fn new(index_file: &Path) -> Self {
+        let writer = |dataset: &str, df: PolarsResult<DataFrame>| {
+            let schema = df.unwrap().schema().clone();
+            StreamingParquetWriter::new(&companion_path(index_file, dataset), schema)
+        };
+        AnalysisWriter {
+            imports: writer("imports", imports_dataframe(&[])),
+            findings: writer("findings", findings_dataframe(&[])),
+            secrets: writer("secrets", secrets_dataframe(&[])),
+        }
+    }
+
+    pub fn write_analysis(
+        &mut self,
+        package: &RepositoryPackage,
+        analysis: PackageAnalysis,
+    ) -> anyhow::Result<()> {
+        let imports = analysis.imports.iter().map(|i| (package, i)).collect_vec();
+        self.imports.write(imports_dataframe(&imports)?)?;
+        let findings = analysis.findings.iter().map(|f| (package, f)).collect_vec();
+        self.findings.write(findings_dataframe(&findings)?)?;
+        let secrets = analysis.secrets.iter().map(|s| (package, s)).collect_vec();
+        self.secrets.write(secrets_dataframe(&secrets)?)?;
+        Ok(())
+    }
+
+    pub fn finish(self) -> anyhow::Result<()> {
+        self.imports.finish()?;
+        self.findings.finish()?;
+        self.secrets.finish()
+    }
+}
+
+fn package_columns(packages: &[&RepositoryPackage]) -> Vec<Column> {
+    vec![
+        Column::new(
+            "project_name".into(),
+            packages
+                .iter()
+                .map(|p| p.project_name.as_str())
+                .collect_vec(),
+        ),
+        Column::new(
+            "project_version".into(),
+            packages
+                .iter()
+                .map(|p| p.project_version.as_str())
+                .collect_vec(),
+        ),
+        Column::new(
+            "project_release".into(),
+            packages.iter().map(|p| p.package_filename()).collect_vec(),
+        ),
+    ]
+}
+
+fn imports_dataframe(rows: &[(&RepositoryPackage, &ImportItem)]) -> PolarsResult<DataFrame> {
+    let packages = rows.iter().map(|(p, _)| *p).collect_vec();
+    let mut columns = package_columns(&packages);
+    columns.extend([
+        Column::new(
+            "path".into(),
+            rows.iter().map(|(_, i)| i.path.as_str()).collect_vec(),
+        ),
+        Column::new(
+            "imported_module".into(),
+            rows.iter()
+                .map(|(_, i)| i.imported_module.as_deref())
+                .collect_vec(),
+        ),
+        Column::new(
+            "parse_error".into(),
+            rows.iter()
+                .map(|(_, i)| i.parse_error.as_deref())
+                .collect_vec(),
+        ),
+    ]);
+    DataFrame::new(columns)
+}
+
+fn findings_dataframe(rows: &[(&RepositoryPackage, &Finding)]) -> PolarsResult<DataFrame> {
+    let packages = rows.iter().map(|(p, _)| *p).collect_vec();
+    let mut columns = package_columns(&packages);
+    columns.extend([
+        Column::new(
+            "path".into(),
+            rows.iter().map(|(_, f)| f.path.as_str()).collect_vec(),
+        ),
+        Column::new(
+            "rule_id".into(),
+            rows.iter().map(|(_, f)| f.rule_id.as_str()).collect_vec(),
+        ),
+        Column::new(
+            "line".into(),
+            rows.iter().map(|(_, f)| f.line as u32).collect_vec(),
+        ),
+    ]);
+    DataFrame::new(columns)
+}
+
+fn secrets_dataframe(rows: &[(&RepositoryPackage, &SecretFinding)]) -> PolarsResult<DataFrame> {
+    let packages = rows.iter().map(|(p, _)| *p).collect_vec();
+    let mut columns = package_columns(&packages);
+    columns.extend([
+        Column::new(
+            "path".into(),
+            rows.iter().map(|(_, s)| s.path.as_str()).collect_vec(),
+        ),
+        Column::new(
+            "rule_id".into(),
+            rows.iter().map(|(_, s)| s.rule_id.as_str()).collect_vec(),
+        ),
+        Column::new(
+            "line".into(),
+            rows.iter().map(|(_, s)| s.line as u32).collect_vec(),
+        ),
+        Column::new(
+            "preview".into(),
+            rows.iter().map(|(_, s)| s.preview.as_str()).collect_vec(),
+        ),
+    ]);
+    DataFrame::new(columns)
+}
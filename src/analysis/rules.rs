@@ -0,0 +1,111 @@
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+use anyhow::Context;
+use lazy_regex::BytesRegex;
+use serde::{Deserialize, Serialize};
+
+static RULES: OnceLock<RuleSet> = OnceLock::new();
+
+const DEFAULT_RULES: &str = include_str!("rules.toml");
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Finding {
+    pub path: String,
+    pub rule_id: String,
+    pub line: usize,
+}
+
+#[derive(Deserialize)]
+struct RuleFile {
+    rule: Vec<RuleDefinition>,
+}
+
+#[derive(Deserialize)]
+struct RuleDefinition {
+    id: String,
+    pattern: String,
+    paths: Option<String>,
+}
+
+struct Rule {
+    id: String,
+    pattern: BytesRegex,
+    paths: Option<BytesRegex>,
+}
+
+/// The rules used to flag suspicious code in accepted files.
+pub struct RuleSet {
+    rules: Vec<Rule>,
+    path: Option<PathBuf>,
+}
+
+impl RuleSet {
+    fn parse(contents: &str, path: Option<PathBuf>) -> anyhow::Result<Self> {
+        let file: RuleFile = toml::from_str(contents)?;
+        let rules = file
+            .rule
+            .into_iter()
+            .map(|rule| {
+                Ok(Rule {
+                    pattern: BytesRegex::new(&rule.pattern)
+                        .with_context(|| format!("Invalid pattern for rule {}", rule.id))?,
+                    paths: rule
+                        .paths
+                        .map(|p| BytesRegex::new(&p))
+                        .transpose()
+                        .with_context(|| format!("Invalid paths for rule {}", rule.id))?,
+                    id: rule.id,
+                })
+            })
+            .collect::<anyhow::Result<_>>()?;
+        Ok(RuleSet { rules, path })
+    }
+
+    pub fn from_path(path: &Path) -> anyhow::Result<Self> {
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("Reading rules from {}", path.display()))?;
+        Self::parse(&contents, Some(path.to_path_buf()))
+    }
+
+    /// The file the rules were loaded from, if they aren't the built-in ones.
+    pub fn path(&self) -> Option<&Path> {
+        self.path.as_deref()
+    }
+
+    pub fn scan(&self, path: &str, data: &[u8]) -> Vec<Finding> {
+        let mut findings = vec![];
+        for rule in &self.rules {
+            if let Some(paths) = &rule.paths {
+                if !paths.is_match(path.as_bytes()) {
+                    continue;
+                }
+            }
+            for m in rule.pattern.find_iter(data) {
+                findings.push(Finding {
+                    path: path.to_string(),
+                    rule_id: rule.id.clone(),
+                    line: line_number(data, m.start()),
+                });
+            }
+        }
+        findings
+    }
+}
+
+pub fn line_number(data: &[u8], offset: usize) -> usize {
+    data[..offset].iter().filter(|b| **b == b'\n').count() + 1
+}
+
+/// Loads the rules from a file, replacing the built-in ones. Must be called before the first
+/// call to `rules()`.
+pub fn init(path: &Path) -> anyhow::Result<()> {
+    let rules = RuleSet::from_path(path)?;
+    RULES
+        .set(rules)
+        .map_err(|_| anyhow::anyhow!("Rules already initialised"))
+}
+
+pub fn rules() -> &'static RuleSet {
+    RULES.get_or_init(|| RuleSet::parse(DEFAULT_RULES, None).unwrap())
+}
@@ -0,0 +1,160 @@
+use std::collections::HashMap;
+
+use lazy_regex::{bytes_regex, BytesRegex, Lazy};
+use serde::{Deserialize, Serialize};
+
+use crate::analysis::rules::line_number;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SecretFinding {
+    pub path: String,
+    pub rule_id: String,
+    pub line: usize,
+    /// The match with the secret itself masked, apart from a short prefix.
+    pub preview: String,
+}
+
+struct SecretRule {
+    id: &'static str,
+    /// The secret is the `secret` group when there is one, otherwise the match is a marker
+    /// that is safe to show, such as the header of a private key.
+    pattern: &'static BytesRegex,
+    paths: Option<&'static BytesRegex>,
+    /// Minimum Shannon entropy in bits per character, to filter out placeholders such as
+    /// `password = "changeme-changeme"`.
+    min_entropy: Option<f64>,
+}
+
+static RULES: Lazy<Vec<SecretRule>> = Lazy::new(|| {
+    vec![
+        SecretRule {
+            id: "aws-access-key-id",
+            pattern: bytes_regex!(r#"\b(?P<secret>(AKIA|ASIA)[0-9A-Z]{16})\b"#),
+            paths: None,
+            min_entropy: None,
+        },
+        SecretRule {
+            id: "aws-secret-access-key",
+            pattern: bytes_regex!(
+                r#"(?i)aws_?secret_?(access_?)?key['"]?\s*[:=]\s*['"]?(?P<secret>[A-Za-z0-9/+]{40})\b"#
+            ),
+            paths: None,
+            min_entropy: Some(4.0),
+        },
+        SecretRule {
+            id: "github-token",
+            pattern: bytes_regex!(
+                r#"\b(?P<secret>(gh[pousr]_[A-Za-z0-9]{36}|github_pat_[A-Za-z0-9_]{82}))\b"#
+            ),
+            paths: None,
+            min_entropy: None,
+        },
+        SecretRule {
+            id: "pypi-token",
+            pattern: bytes_regex!(r#"\b(?P<secret>pypi-AgEIcHlwaS5vcmc[A-Za-z0-9_-]{50,})"#),
+            paths: None,
+            min_entropy: None,
+        },
+        SecretRule {
+            id: "slack-token",
+            pattern: bytes_regex!(r#"\b(?P<secret>xox[baprs]-[0-9A-Za-z-]{10,})"#),
+            paths: None,
+            min_entropy: None,
+        },
+        SecretRule {
+            id: "google-api-key",
+            pattern: bytes_regex!(r#"\b(?P<secret>AIza[0-9A-Za-z_-]{35})\b"#),
+            paths: None,
+            min_entropy: None,
+        },
+        SecretRule {
+            id: "stripe-secret-key",
+            pattern: bytes_regex!(r#"\b(?P<secret>[rs]k_live_[0-9A-Za-z]{24,})\b"#),
+            paths: None,
+            min_entropy: None,
+        },
+        SecretRule {
+            id: "private-key",
+            pattern: bytes_regex!(
+                r#"-----BEGIN ((RSA|DSA|EC|OPENSSH|PGP|ENCRYPTED) )?PRIVATE KEY( BLOCK)?-----"#
+            ),
+            paths: None,
+            min_entropy: None,
+        },
+        SecretRule {
+            id: "generic-secret",
+            pattern: bytes_regex!(
+                r#"(?i)\b\w*(secret|token|passw(or)?d|api_?key)\w*['"]?\s*[:=]\s*['"](?P<secret>[^'"\s]{16,})['"]"#
+            ),
+            paths: None,
+            min_entropy: Some(3.5),
+        },
+        SecretRule {
+            id: "dotenv-value",
+            pattern: bytes_regex!(
+                r#"(?m)^\s*(export\s+)?[A-Za-z_][A-Za-z0-9_]*\s*=\s*['"]?(?P<secret>[^'"\s#]{8,})"#
+            ),
+            paths: Some(bytes_regex!(
+                r#"(^|/)\.env(\.(local|dev|development|prod|production))?$"#
+            )),
+            min_entropy: None,
+        },
+    ]
+});
+
+fn shannon_entropy(value: &[u8]) -> f64 {
+    let mut counts = HashMap::new();
+    for byte in value {
+        *counts.entry(byte).or_insert(0usize) += 1;
+    }
+    let len = value.len() as f64;
+    counts
+        .values()
+        .map(|count| {
+            let p = *count as f64 / len;
+            -p * p.log2()
+        })
+        .sum()
+}
+
+fn redact(secret: &[u8]) -> String {
+    let secret = String::from_utf8_lossy(secret);
+    let prefix: String = secret.chars().take(4).collect();
+    let masked = secret.chars().count().saturating_sub(4).min(16);
+    format!("{prefix}{}", "*".repeat(masked))
+}
+
+pub fn scan_secrets(path: &str, data: &[u8]) -> Vec<SecretFinding> {
+    let mut findings = vec![];
+    for rule in RULES.iter() {
+        if let Some(paths) = rule.paths {
+            if !paths.is_match(path.as_bytes()) {
+                continue;
+            }
+        }
+        for captures in rule.pattern.captures_iter(data) {
+            let whole = captures.get(0).unwrap();
+            let preview = match captures.name("secret") {
+                Some(secret) => {
+                    if let Some(min_entropy) = rule.min_entropy {
+                        if shannon_entropy(secret.as_bytes()) < min_entropy {
+                            continue;
+                        }
+                    }
+                    let mut preview = data[whole.start()..secret.start()].to_vec();
+                    preview.extend(redact(secret.as_bytes()).into_bytes());
+                    preview.extend(&data[secret.end()..whole.end()]);
+                    String::from_utf8_lossy(&preview).trim().to_string()
+                }
+                None => String::from_utf8_lossy(whole.as_bytes()).to_string(),
+            };
+            findings.push(SecretFinding {
+                path: path.to_string(),
+                rule_id: rule.id.to_string(),
+                line: line_number(data, whole.start()),
+                preview,
+            });
+        }
+    }
+    findings
+}
@@ -0,0 +1,314 @@
+use object::elf::DT_NEEDED;
+use object::read::elf::{Dyn, ElfFile, FileHeader};
+use object::read::macho::{FatArch, MachHeader, MachOFatFile32, MachOFatFile64, MachOFile};
+use object::{FileKind, Object};
+use serde::{Deserialize, Serialize};
+
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum BinaryFormat {
+    Elf,
+    MachO,
+    Pe,
+    Pyc,
+    Zip,
+    Gzip,
+    Png,
+    Jpeg,
+    Gif,
+    Webp,
+    Bmp,
+    Ico,
+    Sqlite,
+    Pickle,
+    Numpy,
+    JavaClass,
+}
+
+impl From<BinaryFormat> for &'static str {
+    fn from(val: BinaryFormat) -> Self {
+        match val {
+            BinaryFormat::Elf => "elf",
+            BinaryFormat::MachO => "mach-o",
+            BinaryFormat::Pe => "pe",
+            BinaryFormat::Pyc => "pyc",
+            BinaryFormat::Zip => "zip",
+            BinaryFormat::Gzip => "gzip",
+            BinaryFormat::Png => "png",
+            BinaryFormat::Jpeg => "jpeg",
+            BinaryFormat::Gif => "gif",
+            BinaryFormat::Webp => "webp",
+            BinaryFormat::Bmp => "bmp",
+            BinaryFormat::Ico => "ico",
+            BinaryFormat::Sqlite => "sqlite",
+            BinaryFormat::Pickle => "pickle",
+            BinaryFormat::Numpy => "numpy",
+            BinaryFormat::JavaClass => "java-class",
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct BinaryInfo {
+    pub format: BinaryFormat,
+    /// Architectures of native code, more than one for universal Mach-O binaries.
+    pub architecture: Option<String>,
+    /// Shared libraries native code links against.
+    pub dependencies: Vec<String>,
+    pub pyc_magic: Option<u16>,
+    pub python_version: Option<String>,
+}
+
+impl BinaryInfo {
+    fn new(format: BinaryFormat) -> Self {
+        BinaryInfo {
+            format,
+            architecture: None,
+            dependencies: vec![],
+            pyc_magic: None,
+            python_version: None,
+        }
+    }
+}
+
+/// Identifies a binary file from its magic bytes.
+pub fn classify_binary(data: &[u8]) -> Option<BinaryInfo> {
+    let format = match data {
+        [0x7f, b'E', b'L', b'F', ..] => BinaryFormat::Elf,
+        // Java class files share the magic of universal Mach-O binaries. Where those have the
+        // number of architectures, which is small, class files have their major version, which
+        // starts at 45.
+        [0xca, 0xfe, 0xba, 0xbe, _, _, hi, lo, ..] if u16::from_be_bytes([*hi, *lo]) >= 45 => {
+            BinaryFormat::JavaClass
+        }
+        [0xfe, 0xed, 0xfa, 0xce | 0xcf, ..]
+        | [0xce | 0xcf, 0xfa, 0xed, 0xfe, ..]
+        | [0xca, 0xfe, 0xba, 0xbe | 0xbf, ..] => BinaryFormat::MachO,
+        [b'M', b'Z', ..] => BinaryFormat::Pe,
+        [_, _, b'\r', b'\n', ..] if pyc_python_version(data).is_some() => BinaryFormat::Pyc,
+        [b'P', b'K', 3, 4, ..] | [b'P', b'K', 5, 6, ..] => BinaryFormat::Zip,
+        [0x1f, 0x8b, ..] => BinaryFormat::Gzip,
+        [0x89, b'P', b'N', b'G', ..] => BinaryFormat::Png,
+        [0xff, 0xd8, 0xff, ..] => BinaryFormat::Jpeg,
+        [b'G', b'I', b'F', b'8', ..] => BinaryFormat::Gif,
+        [b'R', b'I', b'F', b'F', _, _, _, _, b'W', b'E', b'B', b'P', ..] => BinaryFormat::Webp,
+        [b'B', b'M', ..] => BinaryFormat::Bmp,
+        [0, 0, 1, 0, ..] => BinaryFormat::Ico,
+        _ if data.starts_with(b"SQLite format 3\0") => BinaryFormat::Sqlite,
+        _ if data.starts_with(b"\x93NUMPY") => BinaryFormat::Numpy,
+        // Protocol 2 and later start with the PROTO opcode.
+        [0x80, 2..=5, ..] => BinaryFormat::Pickle,
+        _ => return None,
+    };
+
+    let mut info = BinaryInfo::new(format);
+    match format {
+        BinaryFormat::Elf | BinaryFormat::MachO | BinaryFormat::Pe => {
+            // Files with the right magic bytes that don't parse are still recorded, just
+            // without the details.
+            if let Some((architecture, dependencies)) = native_details(data) {
+                info.architecture = Some(architecture);
+                info.dependencies = dependencies;
+            }
+        }
+        BinaryFormat::Pyc => {
+            let magic = u16::from_le_bytes([data[0], data[1]]);
+            info.pyc_magic = Some(magic);
+            info.python_version = pyc_python_version(data).map(str::to_string);
+        }
+        _ => {}
+    }
+    Some(info)
+}
+
+fn native_details(data: &[u8]) -> Option<(String, Vec<String>)> {
+    match FileKind::parse(data).ok()? {
+        FileKind::Elf32 => elf_details::<object::elf::FileHeader32<_>>(data),
+        FileKind::Elf64 => elf_details::<object::elf::FileHeader64<_>>(data),
+        FileKind::MachO32 => macho_details::<object::macho::MachHeader32<_>>(data),
+        FileKind::MachO64 => macho_details::<object::macho::MachHeader64<_>>(data),
+        FileKind::MachOFat32 => {
+            let fat = MachOFatFile32::parse(data).ok()?;
+            universal_details(fat.arches().iter().map(|a| a.data(data)))
+        }
+        FileKind::MachOFat64 => {
+            let fat = MachOFatFile64::parse(data).ok()?;
+            universal_details(fat.arches().iter().map(|a| a.data(data)))
+        }
+        FileKind::Pe32 | FileKind::Pe64 => {
+            let file = object::File::parse(data).ok()?;
+            let mut dependencies: Vec<String> = vec![];
+            for import in file.imports().ok()? {
+                let library = String::from_utf8_lossy(import.library()).into_owned();
+                if !dependencies.contains(&library) {
+                    dependencies.push(library);
+                }
+            }
+            Some((architecture_name(file.architecture()), dependencies))
+        }
+        _ => None,
+    }
+}
+
+fn architecture_name(architecture: object::Architecture) -> String {
+    format!("{architecture:?}").to_ascii_lowercase()
+}
+
+fn elf_details<Elf: FileHeader<Endian = object::Endianness>>(
+    data: &[u8],
+) -> Option<(String, Vec<String>)> {
+    let file = ElfFile::<Elf>::parse(data).ok()?;
+    let endian = file.endian();
+    let sections = file.elf_section_table();
+    let mut dependencies = vec![];
+    if let Some((entries, link)) = sections.dynamic(endian, data).ok()? {
+        let strings = sections.strings(endian, data, link).ok()?;
+        for entry in entries {
+            if entry.tag32(endian) == Some(DT_NEEDED) {
+                let name = entry.string(endian, strings).ok()?;
+                dependencies.push(String::from_utf8_lossy(name).into_owned());
+            }
+        }
+    }
+    Some((architecture_name(file.architecture()), dependencies))
+}
+
+fn macho_details<Mach: MachHeader<Endian = object::Endianness>>(
+    data: &[u8],
+) -> Option<(String, Vec<String>)> {
+    let file = MachOFile::<Mach>::parse(data).ok()?;
+    let endian = file.endian();
+    let mut dependencies = vec![];
+    let mut commands = file.macho_load_commands().ok()?;
+    while let Some(command) = commands.next().ok()? {
+        if let Some(dylib) = command.dylib().ok()? {
+            let name = command.string(endian, dylib.dylib.name).ok()?;
+            dependencies.push(String::from_utf8_lossy(name).into_owned());
+        }
+    }
+    Some((architecture_name(file.architecture()), dependencies))
+}
+
+fn universal_details<'a, E>(
+    arches: impl Iterator<Item = Result<&'a [u8], E>>,
+) -> Option<(String, Vec<String>)> {
+    let mut architectures = vec![];
+    let mut dependencies: Vec<String> = vec![];
+    for arch in arches {
+        let (architecture, arch_dependencies) = native_details(arch.ok()?)?;
+        architectures.push(architecture);
+        for dependency in arch_dependencies {
+            if !dependencies.contains(&dependency) {
+                dependencies.push(dependency);
+            }
+        }
+    }
+    Some((architectures.join(","), dependencies))
+}
+
+/// The Python version that wrote a `.pyc` file, from the magic number in its first two bytes.
+/// The ranges follow the history in `importlib/_bootstrap_external.py`.
+fn pyc_python_version(data: &[u8]) -> Option<&'static str> {
+    let magic = u16::from_le_bytes([*data.first()?, *data.get(1)?]);
+    Some(match magic {
+        20121 => "1.5",
+        50823 => "2.0",
+        60202 => "2.1",
+        60717 => "2.2",
+        62011..=62021 => "2.3",
+        62041..=62061 => "2.4",
+        62071..=62131 => "2.5",
+        62151..=62161 => "2.6",
+        62171..=62211 => "2.7",
+        3000..=3131 => "3.0",
+        3141..=3151 => "3.1",
+        3160..=3180 => "3.2",
+        3190..=3230 => "3.3",
+        3250..=3310 => "3.4",
+        3320..=3351 => "3.5",
+        3360..=3379 => "3.6",
+        3390..=3399 => "3.7",
+        3400..=3419 => "3.8",
+        3420..=3429 => "3.9",
+        3430..=3449 => "3.10",
+        3450..=3499 => "3.11",
+        3500..=3549 => "3.12",
+        3550..=3599 => "3.13",
+        3600..=3649 => "3.14",
+        _ => return None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn format(data: &[u8]) -> Option<BinaryFormat> {
+        classify_binary(data).map(|info| info.format)
+    }
+
+    #[test]
+    fn elf_details_of_this_binary() {
+        let data = std::fs::read(std::env::current_exe().unwrap()).unwrap();
+        let info = classify_binary(&data).unwrap();
+        assert_eq!(info.format, BinaryFormat::Elf);
+        assert!(info.architecture.is_some());
+        if cfg!(target_arch = "x86_64") {
+            assert_eq!(info.architecture.as_deref(), Some("x86_64"));
+        }
+        assert!(info.dependencies.iter().any(|d| d.starts_with("libc.so")));
+    }
+
+    #[test]
+    fn java_class_is_not_universal_macho() {
+        // A class file compiled for Java 8, major version 52.
+        let class = [0xca, 0xfe, 0xba, 0xbe, 0x00, 0x00, 0x00, 0x34, 0x00, 0x1d];
+        assert_eq!(format(&class), Some(BinaryFormat::JavaClass));
+        // A universal binary with two architectures.
+        let fat = [0xca, 0xfe, 0xba, 0xbe, 0x00, 0x00, 0x00, 0x02, 0x01, 0x00];
+        let info = classify_binary(&fat).unwrap();
+        assert_eq!(info.format, BinaryFormat::MachO);
+        // The architectures are missing, so there are no details.
+        assert_eq!(info.architecture, None);
+    }
+
+    #[test]
+    fn pyc_magic_numbers() {
+        let cases: [(&[u8], u16, &str); 4] = [
+            (b"\x03\xf3\r\n", 62211, "2.7"),
+            (b"\x33\x0d\r\n", 3379, "3.6"),
+            (b"\xa7\x0d\r\n", 3495, "3.11"),
+            (b"\xcb\x0d\r\n", 3531, "3.12"),
+        ];
+        for (header, magic, version) in cases {
+            let mut data = header.to_vec();
+            data.extend_from_slice(&[0; 12]);
+            let info = classify_binary(&data).unwrap();
+            assert_eq!(info.format, BinaryFormat::Pyc);
+            assert_eq!(info.pyc_magic, Some(magic));
+            assert_eq!(info.python_version.as_deref(), Some(version));
+        }
+    }
+
+    #[test]
+    fn unknown_pyc_magic_is_not_pyc() {
+        assert_eq!(format(b"\xff\xff\r\n\0\0\0\0"), None);
+    }
+
+    #[test]
+    fn data_formats() {
+        let cases: [(&[u8], BinaryFormat); 8] = [
+            (b"PK\x03\x04\x14\x00", BinaryFormat::Zip),
+            (b"\x1f\x8b\x08\x00", BinaryFormat::Gzip),
+            (b"\x89PNG\r\n\x1a\n", BinaryFormat::Png),
+            (b"\xff\xd8\xff\xe0", BinaryFormat::Jpeg),
+            (b"RIFF\x24\x00\x00\x00WEBPVP8 ", BinaryFormat::Webp),
+            (b"SQLite format 3\0", BinaryFormat::Sqlite),
+            (b"\x93NUMPY\x01\x00", BinaryFormat::Numpy),
+            (b"\x80\x04\x95\x00", BinaryFormat::Pickle),
+        ];
+        for (data, expected) in cases {
+            assert_eq!(format(data), Some(expected), "{data:?}");
+        }
+        assert_eq!(format(b"plain text"), None);
+    }
+}
@@ -6,12 +6,16 @@ use content_inspector::{inspect, ContentType as InspectType};
 use git2::{ObjectType, Oid};
 use lazy_regex::regex_is_match;
 
+use crate::archive::binary::{classify_binary, BinaryInfo};
+use crate::metadata::MetadataSource;
+use serde::{Deserialize, Serialize};
+
 pub const KB: usize = 1024;
 pub const MB: usize = 1024 * KB;
 pub const MAX_PYTHON_SIZE: usize = 5 * MB;
 pub const MAX_NON_PYTHON_SIZE: usize = 200 * KB;
 
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
 pub enum SkipReason {
     Binary,
     LongLines,
@@ -19,6 +23,25 @@ pub enum SkipReason {
     Empty,
     VersionControlSystem,
     Venv,
+    Encrypted,
+    UnsupportedCompression,
+    InvalidEntry,
+    Secret,
+}
+
+impl SkipReason {
+    pub const ALL: [SkipReason; 10] = [
+        SkipReason::Binary,
+        SkipReason::LongLines,
+        SkipReason::TooLarge,
+        SkipReason::Empty,
+        SkipReason::VersionControlSystem,
+        SkipReason::Venv,
+        SkipReason::Encrypted,
+        SkipReason::UnsupportedCompression,
+        SkipReason::InvalidEntry,
+        SkipReason::Secret,
+    ];
 }
 
 impl From<SkipReason> for &'static str {
@@ -30,6 +53,10 @@ impl From<SkipReason> for &'static str {
             SkipReason::Empty => "empty",
             SkipReason::VersionControlSystem => "version-control",
             SkipReason::Venv => "virtualenv",
+            SkipReason::Encrypted => "encrypted",
+            SkipReason::UnsupportedCompression => "unsupported-compression",
+            SkipReason::InvalidEntry => "invalid-entry",
+            SkipReason::Secret => "secret",
         }
     }
 }
@@ -38,13 +65,18 @@ pub enum Content {
     Skip {
         path: String,
         archive_path: String,
+        normalized_path: String,
         hash: [u8; 20],
         reason: SkipReason,
         lines: Option<usize>,
+        binary: Option<BinaryInfo>,
+        /// Contents of package metadata files, which are read even when they are skipped.
+        metadata: Option<Vec<u8>>,
     },
     Add {
         path: String,
         archive_path: String,
+        normalized_path: String,
         hash: [u8; 20],
         lines: usize,
         contents: Vec<u8>,
@@ -59,53 +91,186 @@ pub enum Content {
 //     pub lines: Option<usize>
 // }
 
+/// Maps paths inside an archive to paths in the output.
+pub struct PathMapper {
+    prefix: String,
+    root: Option<String>,
+    strip_root: bool,
+    root_from_first_entry: bool,
+}
+
+impl PathMapper {
+    pub fn new(prefix: String, root: Option<&str>, strip_root: bool) -> Self {
+        PathMapper {
+            prefix,
+            root: root.map(normalize_name),
+            strip_root,
+            root_from_first_entry: false,
+        }
+    }
+
+    pub fn strips_root(&self) -> bool {
+        self.strip_root
+    }
+
+    /// Take the root from the first file of the archive instead, for archives read in a single
+    /// pass. Without stripping only the normalized paths depend on it, so the guess is enough.
+    pub fn detect_root_from_first_entry(&mut self) {
+        self.root_from_first_entry = true;
+    }
+
+    /// Called with every path of the archive in order, before it is mapped.
+    pub fn observe(&mut self, archive_path: &str) {
+        if !self.root_from_first_entry {
+            return;
+        }
+        let path = archive_path.trim_start_matches("./");
+        if path.is_empty() || path.ends_with('/') {
+            return;
+        }
+        self.root_from_first_entry = false;
+        if let Some((first, _)) = path.split_once('/') {
+            self.root = Some(normalize_name(first));
+        }
+    }
+
+    /// Use the directory every file in the archive is nested under as the root, if there is
+    /// one. The name derived from the filename is kept otherwise, or if the archive is empty.
+    pub fn detect_root<'a>(&mut self, archive_paths: impl IntoIterator<Item = &'a str>) {
+        let mut common: Option<&str> = None;
+        for path in archive_paths {
+            let path = path.trim_start_matches("./");
+            if path.is_empty() || path.ends_with('/') {
+                continue;
+            }
+            let Some((first, _)) = path.split_once('/') else {
+                return;
+            };
+            match common {
+                Some(root) if root != first => return,
+                _ => common = Some(first),
+            }
+        }
+        if let Some(root) = common {
+            self.root = Some(normalize_name(root));
+        }
+    }
+
+    /// The path relative to the top-level directory of the archive. Sdists nest everything
+    /// under a `name-version/` directory, which changes with every release. The directory name
+    /// doesn't always match the filename exactly, so the comparison ignores case and `-`/`_`.
+    pub fn normalize<'a>(&self, archive_path: &'a str) -> &'a str {
+        let trimmed = archive_path.trim_start_matches("./");
+        let Some(root) = &self.root else {
+            return trimmed;
+        };
+        match trimmed.split_once('/') {
+            Some((first, rest)) if normalize_name(first) == *root => rest,
+            _ => trimmed,
+        }
+    }
+
+    pub fn map(&self, archive_path: &str) -> String {
+        let archive_path = if self.strip_root {
+            self.normalize(archive_path)
+        } else {
+            archive_path
+        };
+        let mut path = format!("{}{}", self.prefix, &archive_path.replace('\n', "_newline"))
+            .replace("//", "/");
+
+        if path.ends_with(".git") {
+            path = path.replace(".git", ".git_");
+        }
+
+        if path.contains("./") {
+            path.replace("./", "")
+        } else {
+            path
+        }
+    }
+}
+
+fn normalize_name(name: &str) -> String {
+    name.to_ascii_lowercase().replace('_', "-")
+}
+
+pub fn hash_contents(data: &[u8]) -> io::Result<[u8; 20]> {
+    let oid = Oid::hash_object(ObjectType::Blob, data)
+        .map_err(|_| io::Error::from(ErrorKind::InvalidInput))?;
+    Ok(oid.as_bytes()[..].try_into().unwrap())
+}
+
 pub fn get_contents<R: Read>(
     size: usize,
     reader: &mut R,
     archive_path: String,
-    prefix: &str,
+    paths: &PathMapper,
 ) -> io::Result<Content> {
     let mut vec = Vec::with_capacity(size);
     reader.read_to_end(&mut vec)?;
 
-    let max_idx = min(1024, vec.len());
-    let content_type = inspect(&vec[..max_idx]);
-
-    let mut path =
-        format!("{prefix}{}", &archive_path.replace('\n', "_newline")).replace("//", "/");
+    let path = paths.map(&archive_path);
+    let normalized_path = paths.normalize(&archive_path).to_string();
+    let hash = hash_contents(&vec)?;
 
-    if path.ends_with(".git") {
-        path = path.replace(".git", ".git_");
+    match check_contents(&vec, size, &path) {
+        Ok(lines) => Ok(Content::Add {
+            path,
+            archive_path,
+            normalized_path,
+            hash,
+            lines,
+            contents: vec,
+        }),
+        Err(Skipped {
+            reason,
+            lines,
+            binary,
+        }) => Ok(Content::Skip {
+            metadata: MetadataSource::from_path(&normalized_path).map(|_| vec),
+            path,
+            archive_path,
+            normalized_path,
+            hash,
+            reason,
+            lines,
+            binary,
+        }),
     }
+}
 
-    let path = if path.contains("./") {
-        path.replace("./", "")
-    } else {
-        path
-    };
+struct Skipped {
+    reason: SkipReason,
+    lines: Option<usize>,
+    binary: Option<BinaryInfo>,
+}
 
-    let oid = Oid::hash_object(ObjectType::Blob, &vec)
-        .map_err(|_| io::Error::from(ErrorKind::InvalidInput))?;
-    let hash: [u8; 20] = oid.as_bytes()[..].try_into().unwrap();
+impl Skipped {
+    fn new(reason: SkipReason, lines: usize) -> Self {
+        Skipped {
+            reason,
+            lines: Some(lines),
+            binary: None,
+        }
+    }
+}
+
+/// The line count of a file that is added to the output, or why it is skipped.
+fn check_contents(vec: &[u8], size: usize, path: &str) -> Result<usize, Skipped> {
+    let max_idx = min(1024, vec.len());
+    let content_type = inspect(&vec[..max_idx]);
 
     if content_type == InspectType::BINARY {
-        return Ok(Content::Skip {
-            path,
-            archive_path,
-            hash,
+        return Err(Skipped {
             reason: SkipReason::Binary,
             lines: None,
+            binary: classify_binary(vec),
         });
     }
 
     if size == 0 {
-        return Ok(Content::Skip {
-            path,
-            archive_path,
-            hash,
-            reason: SkipReason::Empty,
-            lines: Some(0),
-        });
+        return Err(Skipped::new(SkipReason::Empty, 0));
     }
 
     let lines = vec.lines().count();
@@ -113,63 +278,27 @@ pub fn get_contents<R: Read>(
     // Pyarmor files are just big bundles of bytecode. This isn't helpful and causes
     // large repositories. They appear to always start with this token.
     if vec.starts_with("__pyarmor".as_ref()) {
-        return Ok(Content::Skip {
-            path,
-            archive_path,
-            hash,
-            reason: SkipReason::Binary,
-            lines: Some(lines),
-        });
+        return Err(Skipped::new(SkipReason::Binary, lines));
     }
     // Ignore git LFS files
     if vec.starts_with("version https://git-lfs".as_ref()) {
-        return Ok(Content::Skip {
-            path,
-            archive_path,
-            hash,
-            reason: SkipReason::VersionControlSystem,
-            lines: Some(lines),
-        });
+        return Err(Skipped::new(SkipReason::VersionControlSystem, lines));
     }
     // Ignore non-python files above a specific size, and non python files above a different size.
     if path.ends_with(".py") {
         if !(1..=MAX_PYTHON_SIZE).contains(&size) {
-            return Ok(Content::Skip {
-                path,
-                archive_path,
-                hash,
-                reason: SkipReason::TooLarge,
-                lines: Some(lines),
-            });
+            return Err(Skipped::new(SkipReason::TooLarge, lines));
         }
     } else if !(1..=MAX_NON_PYTHON_SIZE).contains(&size) {
-        return Ok(Content::Skip {
-            path,
-            archive_path,
-            hash,
-            reason: SkipReason::TooLarge,
-            lines: Some(lines),
-        });
+        return Err(Skipped::new(SkipReason::TooLarge, lines));
     }
 
-    if regex_is_match!(r#"(^|/)(\.git|\.hg|\.svn)/"#, &path) {
-        return Ok(Content::Skip {
-            path,
-            archive_path,
-            hash,
-            reason: SkipReason::VersionControlSystem,
-            lines: Some(lines),
-        });
+    if regex_is_match!(r#"(^|/)(\.git|\.hg|\.svn)/"#, path) {
+        return Err(Skipped::new(SkipReason::VersionControlSystem, lines));
     }
     // |
-    if regex_is_match!(r#"(^|/)(\.venv|venv|site-packages)/"#, &path) {
-        return Ok(Content::Skip {
-            path,
-            archive_path,
-            hash,
-            reason: SkipReason::Venv,
-            lines: Some(lines),
-        });
+    if regex_is_match!(r#"(^|/)(\.venv|venv|site-packages)/"#, path) {
+        return Err(Skipped::new(SkipReason::Venv, lines));
     }
 
     // The areixio package contains very large python files that contain some kind of obfuscated
@@ -177,20 +306,89 @@ pub fn get_contents<R: Read>(
     // very few lines but are comparatively large.
     let total_lines = vec.iter().filter(|v| **v == b'\n').take(5).count();
     if total_lines < 5 && size >= (50 * KB) {
-        return Ok(Content::Skip {
-            path,
-            archive_path,
-            hash,
-            reason: SkipReason::LongLines,
-            lines: Some(lines),
-        });
+        return Err(Skipped::new(SkipReason::LongLines, lines));
     }
 
-    Ok(Content::Add {
-        path,
-        archive_path,
-        hash,
-        lines,
-        contents: vec,
-    })
+    Ok(lines)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mapper(strip_root: bool) -> PathMapper {
+        PathMapper::new(
+            "packages/qux/qux-2.0.tar.gz/".to_string(),
+            Some("qux-2.0"),
+            strip_root,
+        )
+    }
+
+    #[test]
+    fn root_from_filename() {
+        let paths = mapper(true);
+        assert_eq!(paths.normalize("qux-2.0/setup.py"), "setup.py");
+        assert_eq!(paths.normalize("./Qux_2.0/src/qux.py"), "src/qux.py");
+        assert_eq!(paths.normalize("other/setup.py"), "other/setup.py");
+        assert_eq!(
+            paths.map("qux-2.0/setup.py"),
+            "packages/qux/qux-2.0.tar.gz/setup.py"
+        );
+    }
+
+    #[test]
+    fn detect_root_from_entries() {
+        let mut paths = mapper(true);
+        paths.detect_root([
+            "./Weird_Dir-2.0/",
+            "./Weird_Dir-2.0/setup.py",
+            "./Weird_Dir-2.0/PKG-INFO",
+        ]);
+        assert_eq!(paths.normalize("./Weird_Dir-2.0/setup.py"), "setup.py");
+        assert_eq!(paths.normalize("qux-2.0/setup.py"), "qux-2.0/setup.py");
+    }
+
+    #[test]
+    fn detect_root_keeps_filename_without_common_directory() {
+        let mut paths = mapper(true);
+        paths.detect_root(["a/setup.py", "b/setup.py"]);
+        assert_eq!(paths.normalize("qux-2.0/setup.py"), "setup.py");
+
+        let mut paths = mapper(true);
+        paths.detect_root(["setup.py", "src/qux.py"]);
+        assert_eq!(paths.normalize("src/qux.py"), "src/qux.py");
+
+        let mut paths = mapper(true);
+        paths.detect_root([]);
+        assert_eq!(paths.normalize("qux-2.0/setup.py"), "setup.py");
+    }
+
+    #[test]
+    fn root_from_first_entry() {
+        let mut paths = mapper(false);
+        paths.detect_root_from_first_entry();
+        paths.observe("Weird_Dir-2.0/");
+        paths.observe("Weird_Dir-2.0/setup.py");
+        paths.observe("other/setup.py");
+        assert_eq!(paths.normalize("Weird_Dir-2.0/setup.py"), "setup.py");
+        assert_eq!(paths.normalize("other/setup.py"), "other/setup.py");
+        // Without stripping the output path keeps the directory.
+        assert_eq!(
+            paths.map("Weird_Dir-2.0/setup.py"),
+            "packages/qux/qux-2.0.tar.gz/Weird_Dir-2.0/setup.py"
+        );
+    }
+
+    #[test]
+    fn map_escapes_paths() {
+        let paths = mapper(false);
+        assert_eq!(
+            paths.map("qux-2.0/.git"),
+            "packages/qux/qux-2.0.tar.gz/qux-2.0/.git_"
+        );
+        assert_eq!(
+            paths.map("qux-2.0/a\nb.py"),
+            "packages/qux/qux-2.0.tar.gz/qux-2.0/a_newlineb.py"
+        );
+    }
 }
@@ -1,3 +1,4 @@
+pub mod binary;
 pub mod content;
 pub mod tar;
 pub mod zip;
@@ -1,4 +1,4 @@
-use crate::archive::content::{get_contents, Content};
+use crate::archive::content::{get_contents, Content, PathMapper};
 use crate::archive::{ArchiveItem, ExtractionError};
 use crate::data::IndexItem;
 use anyhow::Result;
@@ -11,41 +11,73 @@ fn get_path<T: io::Read>(entry: &tar::Entry<T>) -> Option<String> {
     entry.path().ok()?.to_str().map(|s| s.to_string())
 }
 
-// I don't know how to generalise these.
+/// Paths of the files in a tar archive, up to the first unreadable entry. Only the headers are
+/// read, the contents are skipped.
+pub fn tar_paths<R: io::Read>(archive: &mut Archive<R>) -> Vec<String> {
+    let Ok(entries) = archive.entries() else {
+        return vec![];
+    };
+    entries
+        .map_while(|entry| entry.ok())
+        .filter(|entry| !entry.header().entry_type().is_dir())
+        .filter_map(|entry| get_path(&entry))
+        .collect()
+}
+
+// I don't know how to generalise these. Unreadable entries are skipped, unless `tolerant` is set,
+// in which case the error ends the archive and the entries read so far are kept.
 pub fn iter_tar_gz_contents<'a>(
     archive: &'a mut Archive<GzDecoder<&'a [u8]>>,
-    prefix: String,
+    mut paths: PathMapper,
+    tolerant: bool,
 ) -> io::Result<impl Iterator<Item = Result<(IndexItem, Option<ArchiveItem>), ExtractionError>> + 'a>
 {
-    let result = archive.entries()?.flatten().filter_map(move |mut entry| {
+    let result = archive.entries()?.filter_map(move |entry| {
+        let mut entry = match entry {
+            Ok(entry) => entry,
+            Err(e) if tolerant => return Some(Err(ExtractionError::IOError(e))),
+            Err(_) => return None,
+        };
         let path = get_path(&entry)?;
         let size = entry.size();
         if path.ends_with('/') {
             return None;
         }
-        let (index_item, data) = match get_contents(size as usize, &mut entry, path, &prefix) {
+        paths.observe(&path);
+        let (index_item, data) = match get_contents(size as usize, &mut entry, path, &paths) {
             Ok(Content::Skip {
                 path,
                 archive_path,
+                normalized_path,
                 hash,
                 reason,
                 lines,
+                binary,
+                metadata,
             }) => {
+                let item = metadata.map(|data| ArchiveItem {
+                    path: path.clone(),
+                    size,
+                    data,
+                });
                 return Some(Ok((
                     IndexItem {
                         path,
                         archive_path,
+                        normalized_path,
                         size,
-                        hash,
+                        hash: Some(hash),
                         skip_reason: Some(reason),
                         lines,
+                        binary,
                     },
-                    None,
+                    item,
                 )));
             }
             Ok(Content::Add {
                 path,
                 archive_path,
+                normalized_path,
                 hash,
                 lines,
                 contents,
@@ -53,10 +85,12 @@ pub fn iter_tar_gz_contents<'a>(
                 IndexItem {
                     path,
                     archive_path,
+                    normalized_path,
                     size,
-                    hash,
+                    hash: Some(hash),
                     skip_reason: None,
                     lines: Some(lines),
+                    binary: None,
                 },
                 contents,
             ),
@@ -74,38 +108,56 @@ pub fn iter_tar_gz_contents<'a>(
 
 pub fn iter_tar_bz_contents<'a>(
     archive: &'a mut Archive<BzDecoder<&'a [u8]>>,
-    prefix: String,
+    mut paths: PathMapper,
+    tolerant: bool,
 ) -> io::Result<impl Iterator<Item = Result<(IndexItem, Option<ArchiveItem>), ExtractionError>> + 'a>
 {
-    let result = archive.entries()?.flatten().filter_map(move |mut entry| {
+    let result = archive.entries()?.filter_map(move |entry| {
+        let mut entry = match entry {
+            Ok(entry) => entry,
+            Err(e) if tolerant => return Some(Err(ExtractionError::IOError(e))),
+            Err(_) => return None,
+        };
         let path = get_path(&entry)?;
         let size = entry.size();
         if path.ends_with('/') {
             return None;
         }
-        let (index_item, data) = match get_contents(size as usize, &mut entry, path, &prefix) {
+        paths.observe(&path);
+        let (index_item, data) = match get_contents(size as usize, &mut entry, path, &paths) {
             Ok(Content::Skip {
                 path,
                 archive_path,
+                normalized_path,
                 hash,
                 reason,
                 lines,
+                binary,
+                metadata,
             }) => {
+                let item = metadata.map(|data| ArchiveItem {
+                    path: path.clone(),
+                    size,
+                    data,
+                });
                 return Some(Ok((
                     IndexItem {
                         path,
                         archive_path,
+                        normalized_path,
                         size,
-                        hash,
+                        hash: Some(hash),
                         skip_reason: Some(reason),
                         lines,
+                        binary,
                     },
-                    None,
+                    item,
                 )));
             }
             Ok(Content::Add {
                 path,
                 archive_path,
+                normalized_path,
                 hash,
                 lines,
                 contents,
@@ -113,10 +165,12 @@ pub fn iter_tar_bz_contents<'a>(
                 IndexItem {
                     path,
                     archive_path,
+                    normalized_path,
                     size,
-                    hash,
+                    hash: Some(hash),
                     skip_reason: None,
                     lines: Some(lines),
+                    binary: None,
                 },
                 contents,
             ),
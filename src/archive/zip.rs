@@ -1,19 +1,19 @@
-use crate::archive::content::{get_contents, Content};
+use crate::archive::content::{get_contents, Content, PathMapper, SkipReason};
 use crate::archive::{ArchiveItem, ExtractionError};
 use crate::data::IndexItem;
 use std::io;
 use std::io::Cursor;
 
+use zip::result::ZipError;
 use zip::ZipArchive;
 
 pub fn iter_zip_contents<'a>(
     zip_archive: &'a mut ZipArchive<Cursor<&'a [u8]>>,
-    prefix: String,
+    paths: PathMapper,
 ) -> io::Result<impl Iterator<Item = Result<(IndexItem, Option<ArchiveItem>), ExtractionError>> + 'a>
 {
     let result = (0..zip_archive.len()).filter_map(move |id| {
-        let file = zip_archive.by_index(id);
-        match file {
+        let error = match zip_archive.by_index(id) {
             Ok(mut zipfile) => {
                 if !zipfile.is_file() {
                     return None;
@@ -24,29 +24,40 @@ pub fn iter_zip_contents<'a>(
                     return None;
                 }
                 let (index_item, data) =
-                    match get_contents(zipfile.size() as usize, &mut zipfile, path, &prefix) {
+                    match get_contents(zipfile.size() as usize, &mut zipfile, path, &paths) {
                         Ok(Content::Skip {
                             path,
                             archive_path,
+                            normalized_path,
                             hash,
                             reason,
                             lines,
+                            binary,
+                            metadata,
                         }) => {
+                            let item = metadata.map(|data| ArchiveItem {
+                                path: path.clone(),
+                                size,
+                                data,
+                            });
                             return Some(Ok((
                                 IndexItem {
                                     path,
                                     archive_path,
+                                    normalized_path,
                                     size,
-                                    hash,
+                                    hash: Some(hash),
                                     skip_reason: Some(reason),
                                     lines,
+                                    binary,
                                 },
-                                None,
+                                item,
                             )));
                         }
                         Ok(Content::Add {
                             path,
                             archive_path,
+                            normalized_path,
                             hash,
                             lines,
                             contents,
@@ -54,10 +65,12 @@ pub fn iter_zip_contents<'a>(
                             IndexItem {
                                 path,
                                 archive_path,
+                                normalized_path,
                                 size,
-                                hash,
+                                hash: Some(hash),
                                 skip_reason: None,
                                 lines: Some(lines),
+                                binary: None,
                             },
                             contents,
                         ),
@@ -70,10 +83,50 @@ pub fn iter_zip_contents<'a>(
                     size,
                     data,
                 };
-                Some(Ok((index_item, Some(item))))
+                return Some(Ok((index_item, Some(item))));
             }
-            Err(e) => Some(Err(ExtractionError::ZipError(e))),
-        }
+            Err(e) => e,
+        };
+        Some(skip_unreadable_entry(zip_archive, id, error, &paths))
     });
     Ok(result)
 }
+
+// Entries that can't be decompressed are recorded as skipped, as long as their headers can be
+// read, so that the rest of the archive is still extracted. The hash is of the raw entry data.
+fn skip_unreadable_entry(
+    zip_archive: &mut ZipArchive<Cursor<&[u8]>>,
+    id: usize,
+    error: ZipError,
+    paths: &PathMapper,
+) -> Result<(IndexItem, Option<ArchiveItem>), ExtractionError> {
+    let reason = match &error {
+        ZipError::UnsupportedArchive(_) => SkipReason::UnsupportedCompression,
+        ZipError::InvalidArchive(_) => SkipReason::InvalidEntry,
+        _ => return Err(ExtractionError::ZipError(error)),
+    };
+    let Ok(raw_file) = zip_archive.by_index_raw(id) else {
+        return Err(ExtractionError::ZipError(error));
+    };
+    let reason = if raw_file.encrypted() {
+        SkipReason::Encrypted
+    } else {
+        reason
+    };
+    let archive_path = raw_file.name().to_string();
+    let size = raw_file.size();
+    // The contents can't be read, and a hash of the compressed bytes wouldn't match the file.
+    Ok((
+        IndexItem {
+            path: paths.map(&archive_path),
+            normalized_path: paths.normalize(&archive_path).to_string(),
+            archive_path,
+            size,
+            hash: None,
+            skip_reason: Some(reason),
+            lines: None,
+            binary: None,
+        },
+        None,
+    ))
+}
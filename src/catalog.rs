@@ -0,0 +1,161 @@
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::path::Path;
+
+use chrono::{DateTime, Utc};
+use indicatif::ParallelProgressIterator;
+use polars_parquet::read::read_metadata;
+use rayon::prelude::*;
+use serde::Serialize;
+
+use crate::data::{schema_version, SCHEMA_VERSION};
+use crate::download::{sha256_file, DownloadState};
+use crate::github;
+use crate::github::status::RepoStatus;
+
+const DATASET_ASSET: &str = "dataset.parquet";
+
+/// Describes everything the project publishes, so that consumers don't need to list the
+/// repositories and their releases themselves.
+#[derive(Debug, Serialize)]
+pub struct Catalog {
+    pub generated_at: DateTime<Utc>,
+    pub toolchain_version: String,
+    /// The schema version this toolchain writes. Datasets record the version they were written
+    /// with.
+    pub schema_version: u32,
+    pub repositories: Vec<CatalogRepository>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct CatalogRepository {
+    pub name: String,
+    pub index: usize,
+    pub url: String,
+    /// Size of the git repository in bytes.
+    pub size: u64,
+    pub capacity: usize,
+    pub batches: Option<BatchRange>,
+    /// Package counts by status: removed, truncated, processed or pending.
+    pub packages: BTreeMap<&'static str, usize>,
+    pub earliest_upload: DateTime<Utc>,
+    pub latest_upload: DateTime<Utc>,
+    pub dataset: Option<CatalogDataset>,
+}
+
+/// Numbers of the first and last `index-N.parquet` batch files in the latest release.
+#[derive(Debug, Serialize)]
+pub struct BatchRange {
+    pub first: usize,
+    pub last: usize,
+}
+
+/// The `dataset.parquet` asset of the latest release. The rows, hash and schema version are
+/// only known when the dataset has been downloaded.
+#[derive(Debug, Serialize)]
+pub struct CatalogDataset {
+    pub url: String,
+    pub size: u64,
+    pub rows: Option<usize>,
+    pub sha256: Option<String>,
+    pub schema_version: Option<u32>,
+}
+
+/// Row count, sha256 and schema version of a downloaded dataset. The hash recorded by
+/// `get-all-indexes` is used when the file hasn't been modified since it was downloaded.
+fn describe_local_dataset(
+    path: &Path,
+    downloads: &DownloadState,
+    relative_path: &str,
+) -> anyhow::Result<(usize, String, u32)> {
+    let metadata = read_metadata(&mut File::open(path)?)?;
+    let file_metadata = path.metadata()?;
+    let modified = DateTime::<Utc>::from(file_metadata.modified()?);
+    let sha256 = match downloads.files.get(relative_path) {
+        Some(file) if file.size == file_metadata.len() && modified <= file.downloaded_at => {
+            file.sha256.clone()
+        }
+        _ => sha256_file(path)?.1,
+    };
+    Ok((metadata.num_rows, sha256, schema_version(path)?))
+}
+
+fn catalog_repository(
+    status: RepoStatus,
+    github_token: &str,
+    datasets_dir: Option<&Path>,
+    downloads: &DownloadState,
+) -> anyhow::Result<CatalogRepository> {
+    let assets = github::releases::get_release_assets(None, github_token, &status.name, "latest")?
+        .unwrap_or_default();
+    let batches = assets
+        .iter()
+        .filter_map(|a| a.name.strip_prefix("index-")?.strip_suffix(".parquet"))
+        .filter_map(|n| n.parse::<usize>().ok())
+        .fold(None, |range, n| match range {
+            None => Some(BatchRange { first: n, last: n }),
+            Some(BatchRange { first, last }) => Some(BatchRange {
+                first: n.min(first),
+                last: n.max(last),
+            }),
+        });
+    let dataset = match assets.iter().find(|a| a.name == DATASET_ASSET) {
+        None => None,
+        Some(asset) => {
+            let relative_path = format!("{}.parquet", status.name);
+            let local = datasets_dir
+                .map(|dir| dir.join(&relative_path))
+                .filter(|path| path.exists())
+                .map(|path| describe_local_dataset(&path, downloads, &relative_path))
+                .transpose()?;
+            Some(CatalogDataset {
+                url: asset.browser_download_url.clone(),
+                size: asset.size,
+                rows: local.as_ref().map(|l| l.0),
+                sha256: local.as_ref().map(|l| l.1.clone()),
+                schema_version: local.map(|l| l.2),
+            })
+        }
+    };
+    let mut packages = BTreeMap::new();
+    for package in status.index.packages() {
+        *packages.entry(package.status()).or_default() += 1;
+    }
+    Ok(CatalogRepository {
+        url: format!("https://github.com/pypi-data/{}", status.name),
+        name: status.name,
+        index: status.idx,
+        size: status.size,
+        capacity: status.index.max_capacity(),
+        batches,
+        packages,
+        earliest_upload: status.stats.earliest_package,
+        latest_upload: status.stats.latest_package,
+        dataset,
+    })
+}
+
+/// Builds the catalog from the status of every repository. `datasets_dir` is a directory written
+/// by `get-all-indexes`, used to describe the contents of the datasets.
+pub fn build_catalog(
+    statuses: Vec<RepoStatus>,
+    github_token: &str,
+    datasets_dir: Option<&Path>,
+) -> anyhow::Result<Catalog> {
+    let downloads = match datasets_dir {
+        Some(dir) => DownloadState::load(dir)?,
+        None => DownloadState::default(),
+    };
+    let mut repositories = statuses
+        .into_par_iter()
+        .progress()
+        .map(|status| catalog_repository(status, github_token, datasets_dir, &downloads))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    repositories.sort_by_key(|r| r.index);
+    Ok(Catalog {
+        generated_at: Utc::now(),
+        toolchain_version: env!("CARGO_PKG_VERSION").to_string(),
+        schema_version: SCHEMA_VERSION,
+        repositories,
+    })
+}
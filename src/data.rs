@@ -2,47 +2,99 @@ use std::fs::File;
 use std::io::BufWriter;
 use std::path::{Path, PathBuf};
 
+use anyhow::bail;
 use itertools::Itertools;
+use polars::io::parquet::write::get_encodings;
 use polars::prelude::*;
+use polars_arrow::array::{Array, BinaryViewArray, FixedSizeBinaryArray, Utf8ViewArray};
+use polars_arrow::datatypes::ArrowSchemaRef;
+use polars_arrow::record_batch::RecordBatch;
+use polars_parquet::read::read_metadata;
+use polars_parquet::write::{
+    CompressionOptions, Encoding, FileWriter, KeyValue, RowGroupIterator, Version, WriteOptions,
+    ZstdLevel as ParquetZstdLevel,
+};
+use serde::{Deserialize, Serialize};
+use tracing::{event, Level};
 
+use crate::analysis::PackageAnalysis;
+use crate::archive::binary::BinaryInfo;
 use crate::archive::content::SkipReason;
+use crate::metadata::PackageMetadata;
 use crate::repository::package::RepositoryPackage;
 
-#[derive(Debug)]
+#[derive(Debug, Serialize, Deserialize)]
 pub struct IndexItem {
     pub path: String,
     pub archive_path: String,
+    pub normalized_path: String,
     pub size: u64,
-    pub hash: [u8; 20],
+    /// Git blob hash of the contents, unless they couldn't be read.
+    pub hash: Option<[u8; 20]>,
     pub skip_reason: Option<SkipReason>,
     pub lines: Option<usize>,
+    pub binary: Option<BinaryInfo>,
 }
 
 #[derive(Debug)]
 pub struct PackageFileIndex<'a> {
     pub package: &'a RepositoryPackage,
     pub items: Vec<IndexItem>,
+    pub truncated: Option<String>,
+    pub metadata: Option<PackageMetadata>,
+    pub analysis: PackageAnalysis,
+    /// Identifier of the package named in the subject of the commit that added the files, for
+    /// sinks that commit them.
+    pub commit_package: Option<String>,
 }
 
 impl<'a> PackageFileIndex<'a> {
-    pub fn new(package: &'a RepositoryPackage, items: Vec<IndexItem>) -> Self {
-        PackageFileIndex { package, items }
+    pub fn new(
+        package: &'a RepositoryPackage,
+        items: Vec<IndexItem>,
+        truncated: Option<String>,
+        metadata: Option<PackageMetadata>,
+        analysis: PackageAnalysis,
+    ) -> Self {
+        PackageFileIndex {
+            package,
+            items,
+            truncated,
+            metadata,
+            analysis,
+            commit_package: None,
+        }
+    }
+
+    /// The schema of the index, for files without any packages.
+    pub fn schema() -> SchemaRef {
+        let url = "https://files.pythonhosted.org/packages/empty-0.0.tar.gz";
+        let package = RepositoryPackage::fake_from_url(url.parse().unwrap());
+        let index = PackageFileIndex::new(&package, vec![], None, None, Default::default());
+        index.into_dataframe().schema().clone()
     }
 
     pub fn into_dataframe(self) -> DataFrame {
         let release = self.package.package_filename();
         let upload_time = self.package.upload_time.naive_utc();
+        let truncated = self.truncated.is_some();
+        let package_type: &'static str = self.package.package_type().into();
+        let wheel_tags = self.package.wheel_tags();
+        let invalid_filename = matches!(wheel_tags, Some(Err(_)));
+        let wheel_tags = wheel_tags.and_then(Result::ok);
+        let wheel_tag_column = |name: &str, tags: Option<&Vec<String>>| {
+            let tags = tags.cloned().unwrap_or_default();
+            string_list_column(name, self.items.iter().map(|_| tags.clone()))
+        };
         let skip_column = Column::new(
             "skip_reason".into(),
             self.items
                 .iter()
-                .map(|x| {
-                    let str_value: &'static str =
-                        x.skip_reason.map(|s| s.into()).unwrap_or_default();
-                    str_value
-                })
+                .map(|x| x.skip_reason.map(<&'static str>::from))
                 .collect_vec(),
-        );
+        )
+        .cast(&skip_reason_dtype())
+        .unwrap();
         let columns = vec![
             Column::new(
                 "project_name".into(),
@@ -68,6 +120,44 @@ impl<'a> PackageFileIndex<'a> {
                 TimeUnit::Milliseconds,
             )
             .into_column(),
+            Column::new(
+                "package_type".into(),
+                self.items.iter().map(|_| package_type).collect_vec(),
+            ),
+            Column::new(
+                "invalid_filename".into(),
+                self.items.iter().map(|_| invalid_filename).collect_vec(),
+            ),
+            Column::new(
+                "wheel_distribution".into(),
+                self.items
+                    .iter()
+                    .map(|_| wheel_tags.as_ref().map(|t| t.distribution.as_str()))
+                    .collect_vec(),
+            ),
+            Column::new(
+                "wheel_version".into(),
+                self.items
+                    .iter()
+                    .map(|_| wheel_tags.as_ref().map(|t| t.version.as_str()))
+                    .collect_vec(),
+            ),
+            Column::new(
+                "wheel_build_tag".into(),
+                self.items
+                    .iter()
+                    .map(|_| wheel_tags.as_ref().and_then(|t| t.build_tag.as_deref()))
+                    .collect_vec(),
+            ),
+            wheel_tag_column(
+                "wheel_python_tags",
+                wheel_tags.as_ref().map(|t| &t.python_tags),
+            ),
+            wheel_tag_column("wheel_abi_tags", wheel_tags.as_ref().map(|t| &t.abi_tags)),
+            wheel_tag_column(
+                "wheel_platform_tags",
+                wheel_tags.as_ref().map(|t| &t.platform_tags),
+            ),
             Column::new(
                 "path".into(),
                 self.items.iter().map(|x| x.path.as_str()).collect_vec(),
@@ -79,20 +169,78 @@ impl<'a> PackageFileIndex<'a> {
                     .map(|x| x.archive_path.as_str())
                     .collect_vec(),
             ),
+            Column::new(
+                "normalized_path".into(),
+                self.items
+                    .iter()
+                    .map(|x| x.normalized_path.as_str())
+                    .collect_vec(),
+            ),
             Column::new(
                 "size".into(),
                 self.items.iter().map(|x| x.size).collect_vec(),
             ),
             Column::new(
                 "hash".into(),
-                self.items.iter().map(|x| x.hash.to_vec()).collect_vec(),
+                self.items
+                    .iter()
+                    .map(|x| x.hash.map(|h| h.to_vec()))
+                    .collect_vec(),
             ),
             skip_column,
             Column::new(
                 "lines".into(),
                 self.items
                     .iter()
-                    .map(|x| (x.lines.unwrap_or_default()) as u64)
+                    .map(|x| x.lines.map(|l| l as u64))
+                    .collect_vec(),
+            ),
+            Column::new(
+                "truncated".into(),
+                self.items.iter().map(|_| truncated).collect_vec(),
+            ),
+            Column::new(
+                "binary_format".into(),
+                self.items
+                    .iter()
+                    .map(|x| x.binary.as_ref().map(|b| <&'static str>::from(b.format)))
+                    .collect_vec(),
+            ),
+            Column::new(
+                "binary_architecture".into(),
+                self.items
+                    .iter()
+                    .map(|x| x.binary.as_ref().and_then(|b| b.architecture.as_deref()))
+                    .collect_vec(),
+            ),
+            string_list_column(
+                "binary_dependencies",
+                self.items.iter().map(|x| {
+                    x.binary
+                        .as_ref()
+                        .map(|b| b.dependencies.clone())
+                        .unwrap_or_default()
+                }),
+            ),
+            Column::new(
+                "pyc_magic".into(),
+                self.items
+                    .iter()
+                    .map(|x| x.binary.as_ref().and_then(|b| b.pyc_magic).map(u32::from))
+                    .collect_vec(),
+            ),
+            Column::new(
+                "pyc_python_version".into(),
+                self.items
+                    .iter()
+                    .map(|x| x.binary.as_ref().and_then(|b| b.python_version.as_deref()))
+                    .collect_vec(),
+            ),
+            Column::new(
+                "commit_package".into(),
+                self.items
+                    .iter()
+                    .map(|_| self.commit_package.as_deref())
                     .collect_vec(),
             ),
         ];
@@ -100,55 +248,484 @@ impl<'a> PackageFileIndex<'a> {
     }
 }
 
-pub struct RepositoryFileIndexWriter {
+/// Version of the layout of the datasets, written to the key-value metadata of every file.
+/// Version 1 files have no version: they store skip reasons as `""` for none and unknown line
+/// counts as `0`. Version 2 files have no `commit_package` column.
+pub const SCHEMA_VERSION: u32 = 3;
+pub const SCHEMA_VERSION_KEY: &str = "schema_version";
+pub const TOOLCHAIN_VERSION_KEY: &str = "toolchain_version";
+
+/// Skip reasons are stored as an enum of every reason, so that files written by different runs
+/// share the same categories.
+pub fn skip_reason_dtype() -> DataType {
+    let categories = SkipReason::ALL.map(<&'static str>::from);
+    create_enum_dtype(Utf8ViewArray::from_slice_values(categories))
+}
+
+/// Rows buffered before they are written out as a row group.
+const ROW_GROUP_SIZE: usize = 100_000;
+
+/// Writes dataframes to a parquet file as they arrive, a row group at a time, so memory stays
+/// bounded. Rows are not sorted; `merge-parquet` sorts the combined dataset. The file is written
+/// under a temporary name and only renamed into place once its footer is written, so a process
+/// that is killed never leaves an unreadable file behind.
+pub struct StreamingParquetWriter {
     path: PathBuf,
-    dataframe: Option<DataFrame>,
+    schema: SchemaRef,
+    fixed_size_binary: Vec<(PlSmallStr, usize)>,
+    key_value_metadata: Vec<KeyValue>,
+    writer: Option<ArrowFileWriter>,
+    buffer: Option<DataFrame>,
+    finished: bool,
 }
 
-impl RepositoryFileIndexWriter {
-    pub fn new(path: &Path) -> Self {
-        Self {
-            dataframe: None,
+struct ArrowFileWriter {
+    writer: FileWriter<BufWriter<File>>,
+    schema: ArrowSchemaRef,
+    encodings: Vec<Vec<Encoding>>,
+    options: WriteOptions,
+}
+
+impl StreamingParquetWriter {
+    pub fn new(path: &Path, schema: SchemaRef) -> Self {
+        StreamingParquetWriter {
             path: path.into(),
+            schema,
+            fixed_size_binary: vec![],
+            key_value_metadata: vec![],
+            writer: None,
+            buffer: None,
+            finished: false,
+        }
+    }
+
+    /// Stores a binary column as fixed-size binary. Polars has no such type, so the column is
+    /// converted as it is written, and read back as binary.
+    pub fn with_fixed_size_binary(mut self, column: &str, size: usize) -> Self {
+        self.fixed_size_binary.push((column.into(), size));
+        self
+    }
+
+    /// Adds an entry to the key-value metadata written in the footer.
+    pub fn with_key_value_metadata(mut self, key: &str, value: String) -> Self {
+        self.key_value_metadata.push(KeyValue {
+            key: key.to_string(),
+            value: Some(value),
+        });
+        self
+    }
+
+    pub fn write(&mut self, df: DataFrame) -> anyhow::Result<()> {
+        if df.is_empty() {
+            return Ok(());
         }
+        match &mut self.buffer {
+            None => self.buffer = Some(df),
+            Some(buffer) => {
+                buffer.vstack_mut(&df)?;
+            }
+        }
+        if self
+            .buffer
+            .as_ref()
+            .is_some_and(|b| b.height() >= ROW_GROUP_SIZE)
+        {
+            self.flush()?;
+        }
+        Ok(())
     }
 
-    pub fn write_index(&mut self, index: PackageFileIndex) {
-        let df = index.into_dataframe();
-        match &mut self.dataframe {
-            None => self.dataframe = Some(df),
-            Some(other_df) => {
-                other_df.vstack_mut(&df).unwrap();
+    fn writer(&mut self) -> anyhow::Result<&mut ArrowFileWriter> {
+        if self.writer.is_none() {
+            let mut schema = self.schema.to_arrow(CompatLevel::newest());
+            for (name, size) in &self.fixed_size_binary {
+                if let Ok(field) = schema.try_get_mut(name) {
+                    field.dtype = ArrowDataType::FixedSizeBinary(*size);
+                }
             }
+            let options = WriteOptions {
+                statistics: StatisticsOptions::full(),
+                compression: CompressionOptions::Zstd(Some(ParquetZstdLevel::try_new(12)?)),
+                version: Version::V1,
+                data_page_size: None,
+            };
+            let w = File::create(self.tmp_path())?;
+            self.writer = Some(ArrowFileWriter {
+                encodings: get_encodings(&schema),
+                writer: FileWriter::try_new(BufWriter::new(w), schema.clone(), options)?,
+                schema: Arc::new(schema),
+                options,
+            });
         }
+        Ok(self.writer.as_mut().unwrap())
     }
 
-    pub fn finish(self) -> anyhow::Result<()> {
-        let mut df = self.dataframe.unwrap();
-        df.sort_in_place(
-            ["path"],
-            SortMultipleOptions::new()
-                .with_multithreaded(true)
-                .with_order_descending(true),
+    fn tmp_path(&self) -> PathBuf {
+        let mut name = self.path.file_name().unwrap_or_default().to_os_string();
+        name.push(".tmp");
+        self.path.with_file_name(name)
+    }
+
+    fn flush(&mut self) -> anyhow::Result<()> {
+        let Some(mut buffer) = self.buffer.take() else {
+            return Ok(());
+        };
+        buffer.as_single_chunk_par();
+        let mut written = 0;
+        let result = self.write_row_groups(&buffer, &mut written);
+        if result.is_err() {
+            // Keep the rows that weren't written, rather than losing them with the error.
+            self.buffer = Some(buffer.slice(written as i64, buffer.height() - written));
+        }
+        result
+    }
+
+    fn write_row_groups(&mut self, buffer: &DataFrame, written: &mut usize) -> anyhow::Result<()> {
+        let writer = self.writer()?;
+        for offset in (0..buffer.height()).step_by(ROW_GROUP_SIZE) {
+            let row_group = buffer.slice(offset as i64, ROW_GROUP_SIZE);
+            for batch in row_group.iter_chunks(CompatLevel::newest(), false) {
+                writer.write_batch(batch)?;
+            }
+            *written += row_group.height();
+        }
+        Ok(())
+    }
+
+    fn finish_file(&mut self) -> anyhow::Result<()> {
+        if self.finished {
+            return Ok(());
+        }
+        self.close()?;
+        std::fs::rename(self.tmp_path(), &self.path)?;
+        Ok(())
+    }
+
+    /// Writes the remaining rows and the footer to the temporary file.
+    fn close(&mut self) -> anyhow::Result<()> {
+        self.finished = true;
+        self.flush()?;
+        let mut key_value_metadata = vec![
+            KeyValue {
+                key: SCHEMA_VERSION_KEY.to_string(),
+                value: Some(SCHEMA_VERSION.to_string()),
+            },
+            KeyValue {
+                key: TOOLCHAIN_VERSION_KEY.to_string(),
+                value: Some(env!("CARGO_PKG_VERSION").to_string()),
+            },
+        ];
+        key_value_metadata.append(&mut self.key_value_metadata);
+        self.writer()?.writer.end(Some(key_value_metadata))?;
+        let writer = self.writer.take().unwrap();
+        let file = writer
+            .writer
+            .into_inner()
+            .into_inner()
+            .map_err(|e| e.into_error())?;
+        file.sync_all()?;
+        Ok(())
+    }
+
+    /// Writes the remaining rows and the footer. A file without any rows is still valid.
+    pub fn finish(mut self) -> anyhow::Result<()> {
+        self.finish_file()
+    }
+}
+
+impl ArrowFileWriter {
+    fn write_batch(&mut self, batch: RecordBatch) -> anyhow::Result<()> {
+        let length = batch.len();
+        let arrays = batch
+            .into_arrays()
+            .into_iter()
+            .zip(self.schema.iter_values())
+            .map(|(array, field)| match field.dtype {
+                ArrowDataType::FixedSizeBinary(size) => to_fixed_size_binary(array, size),
+                _ => Ok(array),
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        let batch = RecordBatch::new(length, self.schema.clone(), arrays);
+        let row_groups = RowGroupIterator::try_new(
+            std::iter::once(Ok(batch)),
+            &self.schema,
+            self.options,
+            self.encodings.clone(),
         )?;
-        let w = File::create(self.path)?;
-        let writer = ParquetWriter::new(BufWriter::new(w))
-            .with_statistics(StatisticsOptions::full())
-            .with_compression(ParquetCompression::Zstd(Some(ZstdLevel::try_new(12)?)));
-        writer.finish(&mut df)?;
+        for row_group in row_groups {
+            self.writer.write(row_group?)?;
+        }
         Ok(())
     }
 }
 
+fn to_fixed_size_binary(array: ArrayRef, size: usize) -> anyhow::Result<ArrayRef> {
+    let Some(array) = array.as_any().downcast_ref::<BinaryViewArray>() else {
+        bail!("Expected a binary column, found {:?}", array.dtype());
+    };
+    let mut values = Vec::with_capacity(array.len() * size);
+    for value in array.iter() {
+        match value {
+            Some(value) if value.len() == size => values.extend_from_slice(value),
+            Some(value) => bail!("Expected {size} bytes, found {}", value.len()),
+            None => values.resize(values.len() + size, 0),
+        }
+    }
+    let array = FixedSizeBinaryArray::new(
+        ArrowDataType::FixedSizeBinary(size),
+        values.into(),
+        array.validity().cloned(),
+    );
+    Ok(array.boxed())
+}
+
+// Close the file when extraction bails out early, so that the rows written so far can still be
+// read. It stays at its temporary path, so that it isn't mistaken for a complete file.
+impl Drop for StreamingParquetWriter {
+    fn drop(&mut self) {
+        if self.finished || (self.writer.is_none() && self.buffer.is_none()) {
+            return;
+        }
+        let tmp_path = self.tmp_path();
+        match self.close() {
+            Ok(()) => event!(
+                Level::WARN,
+                "Left unfinished file at {}",
+                tmp_path.display()
+            ),
+            Err(e) => event!(
+                Level::ERROR,
+                "Error closing unfinished file {}: {e:#}",
+                tmp_path.display()
+            ),
+        }
+    }
+}
+
+pub struct RepositoryFileIndexWriter {
+    writer: StreamingParquetWriter,
+}
+
+impl RepositoryFileIndexWriter {
+    pub fn new(path: &Path) -> Self {
+        Self {
+            writer: index_file_writer(path, PackageFileIndex::schema()),
+        }
+    }
+
+    pub fn write_index(&mut self, index: PackageFileIndex) -> anyhow::Result<()> {
+        self.writer.write(index.into_dataframe())
+    }
+
+    pub fn finish(self) -> anyhow::Result<()> {
+        self.writer.finish()
+    }
+}
+
+pub fn index_file_writer(path: &Path, schema: SchemaRef) -> StreamingParquetWriter {
+    StreamingParquetWriter::new(path, schema).with_fixed_size_binary("hash", 20)
+}
+
+/// A list of strings for every row. The type is given explicitly so that it is also right
+/// when there are no rows.
+pub fn string_list_column(name: &str, values: impl Iterator<Item = Vec<String>>) -> Column {
+    let series = values
+        .map(|v| Series::new(PlSmallStr::EMPTY, v))
+        .collect_vec();
+    Series::new(name.into(), series)
+        .cast(&DataType::List(Box::new(DataType::String)))
+        .unwrap()
+        .into_column()
+}
+
+/// Path of a dataset written next to the file index, e.g. `metadata-123.parquet` for
+/// `index-123.parquet`.
+pub fn companion_path(index_file: &Path, dataset: &str) -> PathBuf {
+    let file_name = index_file
+        .file_name()
+        .and_then(|n| n.to_str())
+        .unwrap_or("index.parquet");
+    let file_name = match file_name.strip_prefix("index") {
+        Some(rest) => format!("{dataset}{rest}"),
+        None => format!("{dataset}-{file_name}"),
+    };
+    index_file.with_file_name(file_name)
+}
+
+/// A value from the key-value metadata of a parquet file.
+fn key_value(path: &Path, key: &str) -> anyhow::Result<Option<String>> {
+    let mut file = File::open(path)?;
+    let metadata = read_metadata(&mut file)?;
+    Ok(metadata
+        .key_value_metadata
+        .into_iter()
+        .flatten()
+        .find(|kv| kv.key == key)
+        .and_then(|kv| kv.value))
+}
+
+/// The schema version from the key-value metadata of a parquet file. Files written before
+/// versioning was added are version 1.
+pub fn schema_version(path: &Path) -> anyhow::Result<u32> {
+    match key_value(path, SCHEMA_VERSION_KEY)? {
+        None => Ok(1),
+        Some(version) => Ok(version.parse()?),
+    }
+}
+
+/// Reads an index file written by any version of the toolchain, upgraded to the current schema.
+/// Columns that didn't exist when the file was written are null. Files of the current version
+/// must have exactly the current schema.
+pub fn scan_index_file(path: &Path) -> anyhow::Result<LazyFrame> {
+    let version = schema_version(path)?;
+    if version > SCHEMA_VERSION {
+        bail!(
+            "{} has schema version {version}, newer than {SCHEMA_VERSION}",
+            path.display()
+        );
+    }
+    let mut df = LazyFrame::scan_parquet(path, Default::default())?;
+    if version < 2 {
+        let without_lines = Series::new(
+            "without_lines".into(),
+            [
+                SkipReason::Binary,
+                SkipReason::Encrypted,
+                SkipReason::UnsupportedCompression,
+                SkipReason::InvalidEntry,
+            ]
+            .map(<&'static str>::from),
+        );
+        df = df.with_columns([
+            when(col("skip_reason").eq(lit("")))
+                .then(lit(NULL).cast(DataType::String))
+                .otherwise(col("skip_reason"))
+                .alias("skip_reason"),
+            when(
+                col("lines")
+                    .eq(lit(0u64))
+                    .and(col("skip_reason").is_in(lit(without_lines))),
+            )
+            .then(lit(NULL).cast(DataType::UInt64))
+            .otherwise(col("lines"))
+            .alias("lines"),
+        ]);
+    }
+    let existing = df.collect_schema()?;
+    let schema = PackageFileIndex::schema();
+    for (name, dtype) in existing.iter() {
+        match schema.get(name) {
+            // The merged dataset has the repository column added on top of the index.
+            None if name == "repository" => {}
+            None => bail!("{}: unexpected column {name}", path.display()),
+            Some(expected) if version == SCHEMA_VERSION && dtype != expected => bail!(
+                "{}: column {name} is {dtype}, expected {expected}",
+                path.display()
+            ),
+            Some(_) => {}
+        }
+    }
+    let mut columns = schema
+        .iter()
+        .map(|(name, dtype)| {
+            // Columns that are already the right type are left as they are, so that filters
+            // can be pushed down to the scan.
+            match existing.get(name) {
+                Some(existing) if existing == dtype => Ok(col(name.clone())),
+                Some(_) => Ok(col(name.clone()).strict_cast(dtype.clone())),
+                None if version < SCHEMA_VERSION => {
+                    Ok(lit(NULL).cast(dtype.clone()).alias(name.clone()))
+                }
+                None => bail!("{}: missing column {name}", path.display()),
+            }
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    if existing.contains("repository") {
+        columns.push(col("repository"));
+    }
+    Ok(df.select(columns))
+}
+
+/// Reads a file to merge. The repository column of a merged dataset is added again once the
+/// inputs are combined.
+fn scan_input(path: &Path) -> anyhow::Result<LazyFrame> {
+    Ok(scan_index_file(path)?.drop_no_validate(["repository"]))
+}
+
+/// Key-value metadata of a merged dataset, listing the batch files it contains so that they
+/// aren't merged twice.
+pub const MERGED_FILES_KEY: &str = "merged_files";
+
+/// The batch files a merged dataset contains.
+pub fn read_merged_files(path: &Path) -> anyhow::Result<Vec<String>> {
+    match key_value(path, MERGED_FILES_KEY)? {
+        Some(files) => Ok(serde_json::from_str(&files)?),
+        None => Ok(vec![]),
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct MergeSummary {
+    /// Rows of files that weren't in the dataset.
+    pub added: usize,
+    /// Rows that replaced an older extraction of the same file in the dataset.
+    pub replaced: usize,
+    /// Rows of the dataset that were removed, because their release was removed or they were
+    /// duplicates.
+    pub dropped: usize,
+    /// Rows of the batch files that weren't merged, because their release was removed or a later
+    /// batch file has the same file.
+    pub discarded: usize,
+    /// Batch files that were already merged into the dataset.
+    pub skipped_files: Vec<String>,
+}
+
+/// Adds batch files to an existing dataset, or creates a new one. Batch files are given oldest
+/// first: when a file of a release was extracted more than once, the newest extraction is kept.
 pub fn merge_parquet_files(
-    input_path: &Path,
+    existing: Option<&Path>,
+    batch_files: &[PathBuf],
     output_path: &Path,
     repo_id: usize,
-) -> Result<(), anyhow::Error> {
-    let mut df = LazyFrame::scan_parquet(
-        input_path.join("*.parquet").to_str().unwrap(),
-        Default::default(),
-    )?;
+    removed_releases: Vec<String>,
+) -> Result<MergeSummary, anyhow::Error> {
+    let mut summary = MergeSummary::default();
+    let mut merged_files = vec![];
+    let mut inputs = vec![];
+    if let Some(existing) = existing {
+        merged_files = read_merged_files(existing)?;
+        inputs.push(scan_input(existing)?.with_column(lit(true).alias("_existing")));
+    }
+    for path in batch_files {
+        let Some(name) = path.file_name().and_then(|n| n.to_str()) else {
+            bail!("Invalid batch file {}", path.display());
+        };
+        if merged_files.iter().any(|f| f == name) {
+            summary.skipped_files.push(name.to_string());
+            continue;
+        }
+        inputs.push(scan_input(path)?.with_column(lit(false).alias("_existing")));
+        merged_files.push(name.to_string());
+    }
+    if inputs.is_empty() {
+        bail!("Nothing to merge");
+    }
+
+    let key = [col("project_release"), col("path")];
+    let df = concat(inputs, UnionArgs::default())?
+        .with_column(col("_existing").any(true).over(key).alias("_in_dataset"))
+        .collect()?;
+    let existing_rows = df.column("_existing")?.bool()?.sum().unwrap_or_default() as usize;
+    let batch_rows = df.height() - existing_rows;
+
+    let mut df = df.lazy().unique_stable(
+        Some(vec!["project_release".into(), "path".into()]),
+        UniqueKeepStrategy::Last,
+    );
+    if !removed_releases.is_empty() {
+        let removed = Series::new("removed_releases".into(), removed_releases);
+        df = df.filter(col("project_release").is_in(lit(removed)).not());
+    }
     df = df.sort(
         ["path"],
         SortMultipleOptions::new()
@@ -162,11 +739,150 @@ pub fn merge_parquet_files(
             .alias("repository")
             .cast(DataType::UInt32),
     );
-    let mut df = df.collect()?;
-    let w = File::create(output_path)?;
-    let writer = ParquetWriter::new(BufWriter::new(w))
-        .with_statistics(StatisticsOptions::full())
-        .with_compression(ParquetCompression::Zstd(Some(ZstdLevel::try_new(12)?)));
-    writer.finish(&mut df)?;
-    Ok(())
+    let df = df.collect()?;
+
+    let from_existing = df.column("_existing")?.bool()?;
+    let in_dataset = df.column("_in_dataset")?.bool()?;
+    let kept = from_existing.sum().unwrap_or_default() as usize;
+    summary.replaced = (&!from_existing & in_dataset).sum().unwrap_or_default() as usize;
+    summary.added = df.height() - kept - summary.replaced;
+    summary.dropped = existing_rows - kept - summary.replaced;
+    summary.discarded = batch_rows - summary.added - summary.replaced;
+
+    let df = df.drop_many(["_existing", "_in_dataset"]);
+    let mut writer = index_file_writer(output_path, df.schema().clone())
+        .with_key_value_metadata(MERGED_FILES_KEY, serde_json::to_string(&merged_files)?);
+    writer.write(df)?;
+    writer.finish()?;
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("pypi-data-{}-{name}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn rows(values: &[u64]) -> DataFrame {
+        df!("value" => values).unwrap()
+    }
+
+    fn write_batch(path: &Path, files: &[(&str, &str)]) {
+        let mut writer = RepositoryFileIndexWriter::new(path);
+        for (release, file) in files {
+            let url = format!("https://files.pythonhosted.org/packages/{release}");
+            let package = RepositoryPackage::fake_from_url(url.parse().unwrap());
+            let item = IndexItem {
+                path: file.to_string(),
+                archive_path: file.to_string(),
+                normalized_path: file.to_string(),
+                size: 1,
+                hash: Some([0; 20]),
+                skip_reason: None,
+                lines: Some(1),
+                binary: None,
+            };
+            let index = PackageFileIndex::new(&package, vec![item], None, None, Default::default());
+            writer.write_index(index).unwrap();
+        }
+        writer.finish().unwrap();
+    }
+
+    #[test]
+    fn finish_renames_into_place() {
+        let path = test_dir("finish").join("out.parquet");
+        let mut writer = StreamingParquetWriter::new(&path, rows(&[]).schema().clone());
+        writer.write(rows(&[1, 2, 3])).unwrap();
+        writer.finish().unwrap();
+
+        assert!(!path.with_file_name("out.parquet.tmp").exists());
+        let df = LazyFrame::scan_parquet(&path, Default::default())
+            .unwrap()
+            .collect()
+            .unwrap();
+        assert_eq!(df.height(), 3);
+        assert_eq!(schema_version(&path).unwrap(), SCHEMA_VERSION);
+    }
+
+    #[test]
+    fn drop_leaves_unfinished_file_at_tmp_path() {
+        let path = test_dir("drop").join("out.parquet");
+        let mut writer = StreamingParquetWriter::new(&path, rows(&[]).schema().clone());
+        writer.write(rows(&[1, 2])).unwrap();
+        drop(writer);
+
+        assert!(!path.exists());
+        let tmp_path = path.with_file_name("out.parquet.tmp");
+        let df = LazyFrame::scan_parquet(&tmp_path, Default::default())
+            .unwrap()
+            .collect()
+            .unwrap();
+        assert_eq!(df.height(), 2);
+    }
+
+    #[test]
+    fn drop_without_rows_writes_nothing() {
+        let path = test_dir("unused").join("out.parquet");
+        drop(StreamingParquetWriter::new(
+            &path,
+            rows(&[]).schema().clone(),
+        ));
+
+        assert!(!path.exists());
+        assert!(!path.with_file_name("out.parquet.tmp").exists());
+    }
+
+    #[test]
+    fn merge_counts_every_row() {
+        let dir = test_dir("merge");
+        let first = dir.join("index-1.parquet");
+        let second = dir.join("index-2.parquet");
+        write_batch(&first, &[("a-1.0.tar.gz", "x"), ("a-1.0.tar.gz", "y")]);
+        write_batch(&second, &[("a-1.0.tar.gz", "x"), ("b-1.0.tar.gz", "z")]);
+
+        let dataset = dir.join("dataset.parquet");
+        let summary = merge_parquet_files(None, &[first, second], &dataset, 1, vec![]).unwrap();
+        assert_eq!(
+            (
+                summary.added,
+                summary.replaced,
+                summary.dropped,
+                summary.discarded
+            ),
+            (3, 0, 0, 1)
+        );
+
+        let third = dir.join("index-3.parquet");
+        write_batch(&third, &[("a-1.0.tar.gz", "y"), ("b-1.0.tar.gz", "w")]);
+        let merged = dir.join("merged.parquet");
+        let removed = vec!["b-1.0.tar.gz".to_string()];
+        let summary = merge_parquet_files(
+            Some(&dataset),
+            std::slice::from_ref(&third),
+            &merged,
+            1,
+            removed,
+        )
+        .unwrap();
+        assert_eq!(
+            (
+                summary.added,
+                summary.replaced,
+                summary.dropped,
+                summary.discarded
+            ),
+            (0, 1, 1, 1)
+        );
+        assert_eq!(
+            read_merged_files(&merged).unwrap(),
+            ["index-1.parquet", "index-2.parquet", "index-3.parquet"]
+        );
+
+        let summary = merge_parquet_files(Some(&merged), &[third], &dataset, 1, vec![]).unwrap();
+        assert_eq!(summary.skipped_files, ["index-3.parquet"]);
+    }
 }
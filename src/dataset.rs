@@ -0,0 +1,436 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use anyhow::{bail, Context};
+use git2::{ObjectType, Oid};
+use itertools::Itertools;
+use polars::io::HiveOptions;
+use polars::prelude::*;
+use polars_parquet::read::read_metadata;
+use serde::{Deserialize, Serialize};
+
+use crate::data::{index_file_writer, scan_index_file, SCHEMA_VERSION};
+use crate::github::create::REPO_CODE_PREFIX;
+use crate::repository::package::normalize_project_name;
+
+const MANIFEST_FILE: &str = "manifest.json";
+const PARTITION_FILE: &str = "data.parquet";
+
+/// How the combined dataset is split into hive-style directories.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Partitioning {
+    UploadMonth,
+    Repository,
+}
+
+impl FromStr for Partitioning {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "upload-month" => Ok(Partitioning::UploadMonth),
+            "repository" => Ok(Partitioning::Repository),
+            _ => Err(format!(
+                "Unknown partitioning {s}. Expected upload-month or repository"
+            )),
+        }
+    }
+}
+
+impl Partitioning {
+    /// The partition columns, computed from the index. They are part of the directory names
+    /// rather than stored in the files.
+    fn columns(self) -> Vec<(&'static str, Expr)> {
+        match self {
+            Partitioning::UploadMonth => vec![
+                ("year", col("uploaded_on").dt().year()),
+                ("month", col("uploaded_on").dt().month()),
+            ],
+            Partitioning::Repository => vec![("repository", col("repository"))],
+        }
+    }
+
+    fn with_columns(self, df: LazyFrame) -> LazyFrame {
+        df.with_columns(
+            self.columns()
+                .into_iter()
+                .map(|(name, expr)| expr.cast(DataType::Int64).alias(name))
+                .collect_vec(),
+        )
+    }
+
+    fn path(self, values: &[i64]) -> String {
+        self.columns()
+            .iter()
+            .zip(values)
+            .map(|((name, _), value)| format!("{name}={value}"))
+            .join("/")
+    }
+
+    /// The partition columns, which aren't stored in the partition files.
+    fn literals(self, values: &[i64]) -> Vec<Expr> {
+        self.columns()
+            .iter()
+            .zip(values)
+            .map(|((name, _), value)| match *name {
+                "repository" => lit(*value).cast(DataType::UInt32).alias(*name),
+                _ => lit(*value).alias(*name),
+            })
+            .collect()
+    }
+
+    fn filter(self, values: &[i64]) -> Expr {
+        self.columns()
+            .iter()
+            .zip(values)
+            .map(|((name, _), value)| col(*name).eq(lit(*value)))
+            .reduce(|a, b| a.and(b))
+            .unwrap()
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ManifestInput {
+    pub file: String,
+    pub repository: u32,
+    pub rows: usize,
+    /// Git blob hash of the file, like the hashes in the index.
+    pub hash: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ManifestPartition {
+    pub path: String,
+    pub values: Vec<i64>,
+    pub rows: usize,
+    pub hash: String,
+    pub repositories: Vec<u32>,
+}
+
+/// Describes the dataset, and records the inputs it was built from so that the next build
+/// only rewrites the partitions whose inputs changed.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Manifest {
+    pub partitioning: Partitioning,
+    pub schema_version: u32,
+    pub toolchain_version: String,
+    pub inputs: Vec<ManifestInput>,
+    pub partitions: Vec<ManifestPartition>,
+}
+
+impl Manifest {
+    fn load(output_dir: &Path) -> anyhow::Result<Option<Manifest>> {
+        let path = output_dir.join(MANIFEST_FILE);
+        if !path.exists() {
+            return Ok(None);
+        }
+        let reader = BufReader::new(File::open(&path)?);
+        Ok(Some(serde_json::from_reader(reader)?))
+    }
+
+    fn save(&self, output_dir: &Path) -> anyhow::Result<()> {
+        let writer = BufWriter::new(File::create(output_dir.join(MANIFEST_FILE))?);
+        serde_json::to_writer_pretty(writer, self)?;
+        Ok(())
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct BuildSummary {
+    pub written: usize,
+    pub removed: usize,
+    pub unchanged: usize,
+}
+
+fn hash_file(path: &Path) -> anyhow::Result<String> {
+    Ok(Oid::hash_file(ObjectType::Blob, path)?.to_string())
+}
+
+/// Removes a partition file, and the directories that are left empty.
+fn remove_partition(output_dir: &Path, path: &str) -> anyhow::Result<()> {
+    let file = output_dir.join(path);
+    if file.exists() {
+        std::fs::remove_file(&file)?;
+    }
+    for dir in file.ancestors().skip(1) {
+        if dir == output_dir || std::fs::remove_dir(dir).is_err() {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// The repository of a `pypi-mirror-N.parquet` file, as written by `get-all-indexes`.
+fn repository_id(path: &Path) -> anyhow::Result<u32> {
+    let file = path
+        .file_name()
+        .and_then(|n| n.to_str())
+        .unwrap_or_default();
+    let Some(repository) = file
+        .strip_prefix(REPO_CODE_PREFIX)
+        .and_then(|f| f.strip_suffix(".parquet"))
+        .and_then(|id| id.parse().ok())
+    else {
+        bail!(
+            "Expected {REPO_CODE_PREFIX}N.parquet, found {}",
+            path.display()
+        );
+    };
+    Ok(repository)
+}
+
+fn read_input(path: &Path) -> anyhow::Result<ManifestInput> {
+    let metadata = read_metadata(&mut File::open(path)?)?;
+    Ok(ManifestInput {
+        file: path.file_name().unwrap().to_string_lossy().to_string(),
+        repository: repository_id(path)?,
+        rows: metadata.num_rows,
+        hash: hash_file(path)?,
+    })
+}
+
+fn scan_repository_file(path: &Path, repository: u32) -> anyhow::Result<LazyFrame> {
+    let df = scan_index_file(path)?;
+    Ok(df.with_column(lit(repository).cast(DataType::UInt32).alias("repository")))
+}
+
+fn scan_input(input_dir: &Path, input: &ManifestInput) -> anyhow::Result<LazyFrame> {
+    scan_repository_file(&input_dir.join(&input.file), input.repository)
+}
+
+fn parquet_files(dir: &Path) -> anyhow::Result<Vec<PathBuf>> {
+    Ok(std::fs::read_dir(dir)?
+        .map(|entry| entry.map(|e| e.path()))
+        .filter_ok(|path| path.extension().is_some_and(|ext| ext == "parquet"))
+        .collect::<Result<Vec<_>, _>>()?)
+}
+
+/// Reads the index of all repositories from one of:
+/// * a directory of `pypi-mirror-N.parquet` files, as written by `get-all-indexes`
+/// * a directory written by `build-dataset`
+/// * a single index or dataset file
+pub fn scan_dataset(path: &Path) -> anyhow::Result<LazyFrame> {
+    if path.is_file() {
+        return scan_index_file(path);
+    }
+    if let Some(manifest) = Manifest::load(path)? {
+        // The partition values are added from the manifest. Polars would otherwise detect them
+        // from the paths, and its hive scan panics when filters are pushed down to it.
+        let frames = manifest
+            .partitions
+            .iter()
+            .map(|partition| {
+                let args = ScanArgsParquet {
+                    hive_options: HiveOptions {
+                        enabled: Some(false),
+                        ..Default::default()
+                    },
+                    ..Default::default()
+                };
+                let df = LazyFrame::scan_parquet(path.join(&partition.path), args)?;
+                Ok(df.with_columns(manifest.partitioning.literals(&partition.values)))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        if frames.is_empty() {
+            bail!("No partitions in {}", path.display());
+        }
+        return Ok(concat(frames, UnionArgs::default())?);
+    }
+    let frames = parquet_files(path)?
+        .iter()
+        .map(|file| scan_repository_file(file, repository_id(file)?))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    if frames.is_empty() {
+        bail!("No parquet files in {}", path.display());
+    }
+    Ok(concat(frames, UnionArgs::default())?)
+}
+
+/// The partitions with rows from an input.
+fn input_partitions(
+    input_dir: &Path,
+    input: &ManifestInput,
+    partitioning: Partitioning,
+) -> anyhow::Result<Vec<Vec<i64>>> {
+    let names = partitioning.columns().iter().map(|(n, _)| *n).collect_vec();
+    let df = partitioning
+        .with_columns(scan_input(input_dir, input)?)
+        .select(names.iter().map(|n| col(*n)).collect_vec())
+        .unique(None, UniqueKeepStrategy::Any)
+        .collect()?;
+    let columns = names
+        .iter()
+        .map(|n| Ok(df.column(n)?.i64()?.clone()))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    Ok((0..df.height())
+        .map(|i| {
+            columns
+                .iter()
+                .map(|c| c.get(i).unwrap_or_default())
+                .collect()
+        })
+        .collect())
+}
+
+/// Combines the per-repository datasets in `input_dir` into a hive-partitioned dataset with a
+/// manifest. Partitions whose inputs are unchanged since the last build are kept as they are.
+pub fn build_dataset(
+    input_dir: &Path,
+    output_dir: &Path,
+    partitioning: Partitioning,
+) -> anyhow::Result<BuildSummary> {
+    let inputs = parquet_files(input_dir)?
+        .iter()
+        .map(|path| read_input(path))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    let inputs: BTreeMap<u32, ManifestInput> =
+        inputs.into_iter().map(|i| (i.repository, i)).collect();
+
+    std::fs::create_dir_all(output_dir)?;
+    let mut previous = Manifest::load(output_dir)?;
+    // A dataset with a different layout is rebuilt from scratch.
+    if let Some(manifest) =
+        previous.take_if(|m| m.partitioning != partitioning || m.schema_version != SCHEMA_VERSION)
+    {
+        for partition in manifest.partitions {
+            remove_partition(output_dir, &partition.path)?;
+        }
+    }
+    let (previous_inputs, previous_partitions) = match previous {
+        Some(m) => (m.inputs, m.partitions),
+        None => (vec![], vec![]),
+    };
+
+    let mut changed: BTreeSet<u32> = previous_inputs
+        .iter()
+        .filter(|p| inputs.get(&p.repository).is_none_or(|i| i.hash != p.hash))
+        .map(|p| p.repository)
+        .collect();
+    changed.extend(
+        inputs
+            .keys()
+            .filter(|r| !previous_inputs.iter().any(|p| p.repository == **r)),
+    );
+
+    // Which repositories may have rows in each partition that has to be rewritten.
+    let mut affected: BTreeMap<Vec<i64>, BTreeSet<u32>> = BTreeMap::new();
+    let mut partitions = vec![];
+    for partition in previous_partitions {
+        if partition.repositories.iter().any(|r| changed.contains(r)) {
+            affected.entry(partition.values).or_default().extend(
+                partition
+                    .repositories
+                    .iter()
+                    .filter(|r| inputs.contains_key(r)),
+            );
+        } else {
+            partitions.push(partition);
+        }
+    }
+    let mut summary = BuildSummary {
+        unchanged: partitions.len(),
+        ..Default::default()
+    };
+    for repository in &changed {
+        let Some(input) = inputs.get(repository) else {
+            continue;
+        };
+        for values in input_partitions(input_dir, input, partitioning)? {
+            affected.entry(values).or_default().insert(*repository);
+        }
+    }
+
+    for (values, repositories) in affected {
+        let path = partitioning.path(&values);
+        let dir = output_dir.join(&path);
+        let file = dir.join(PARTITION_FILE);
+        let frames = repositories
+            .iter()
+            .map(|r| scan_input(input_dir, &inputs[r]))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        let mut df = match frames.is_empty() {
+            true => DataFrame::empty(),
+            false => partitioning
+                .with_columns(concat(frames, UnionArgs::default())?)
+                .filter(partitioning.filter(&values))
+                .collect()?,
+        };
+        if df.is_empty() {
+            if file.exists() {
+                remove_partition(output_dir, &format!("{path}/{PARTITION_FILE}"))?;
+                summary.removed += 1;
+            }
+            continue;
+        }
+        let repositories = df
+            .column("repository")?
+            .unique()?
+            .cast(&DataType::UInt32)?
+            .u32()?
+            .into_no_null_iter()
+            .sorted()
+            .collect_vec();
+        df = df.drop_many(partitioning.columns().iter().map(|(n, _)| *n));
+        let normalized = df
+            .column("project_name")?
+            .str()?
+            .apply_values(|name| normalize_project_name(name).into())
+            .with_name("normalized_project_name".into());
+        df.insert_column(1, normalized.into_column())?;
+
+        std::fs::create_dir_all(&dir)?;
+        let rows = df.height();
+        let mut writer = index_file_writer(&file, df.schema().clone());
+        writer.write(df)?;
+        writer
+            .finish()
+            .with_context(|| format!("Error writing {}", file.display()))?;
+        partitions.push(ManifestPartition {
+            path: format!("{path}/{PARTITION_FILE}"),
+            values,
+            rows,
+            hash: hash_file(&file)?,
+            repositories,
+        });
+        summary.written += 1;
+    }
+
+    partitions.sort_by(|a, b| a.values.cmp(&b.values));
+    let manifest = Manifest {
+        partitioning,
+        schema_version: SCHEMA_VERSION,
+        toolchain_version: env!("CARGO_PKG_VERSION").to_string(),
+        inputs: inputs.into_values().collect(),
+        partitions,
+    };
+    manifest.save(output_dir)?;
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_partitioning() {
+        assert_eq!("upload-month".parse(), Ok(Partitioning::UploadMonth));
+        assert_eq!("repository".parse(), Ok(Partitioning::Repository));
+        assert_eq!(
+            "month".parse::<Partitioning>(),
+            Err("Unknown partitioning month. Expected upload-month or repository".to_string())
+        );
+    }
+
+    #[test]
+    fn partitioning_matches_manifest_names() {
+        // The manifest stores the partitioning with serde, which must agree with the CLI names.
+        for partitioning in [Partitioning::UploadMonth, Partitioning::Repository] {
+            let name = serde_json::to_value(partitioning).unwrap();
+            assert_eq!(name.as_str().unwrap().parse(), Ok(partitioning));
+        }
+    }
+}
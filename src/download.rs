@@ -0,0 +1,352 @@
+use std::collections::BTreeMap;
+use std::fs::{File, OpenOptions};
+use std::io::{BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::thread::sleep;
+use std::time::Duration;
+
+use anyhow::{bail, Context};
+use chrono::{DateTime, Utc};
+use indicatif::ParallelProgressIterator;
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::github;
+use crate::github::projects::DataRepo;
+use crate::http::HttpClient;
+
+const STATE_FILE: &str = "downloads.json";
+
+/// Files published by each repository.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Asset {
+    /// The merged `dataset.parquet`, saved as `<repo>.parquet`.
+    Dataset,
+    /// The `index.json` of the repository, saved as `indexes/<repo>.json`.
+    Index,
+    /// The `index-*.parquet` batch files of the latest release, saved in `batches/<repo>/`.
+    Batches,
+}
+
+impl FromStr for Asset {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "dataset" => Ok(Asset::Dataset),
+            "index" => Ok(Asset::Index),
+            "batches" => Ok(Asset::Batches),
+            _ => Err(format!(
+                "Unknown asset {s}. Expected dataset, index or batches"
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DownloadedFile {
+    pub url: String,
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+    pub size: u64,
+    pub sha256: String,
+    pub downloaded_at: DateTime<Utc>,
+}
+
+/// Validators of the response a `.part` file was started from, which are needed to resume it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct PartialFile {
+    etag: Option<String>,
+    last_modified: Option<String>,
+}
+
+impl PartialFile {
+    fn if_range(&self) -> Option<&str> {
+        self.etag.as_deref().or(self.last_modified.as_deref())
+    }
+}
+
+/// Kept in the output directory, by path relative to it.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct DownloadState {
+    pub files: BTreeMap<String, DownloadedFile>,
+    #[serde(default)]
+    partial: BTreeMap<String, PartialFile>,
+}
+
+impl DownloadState {
+    pub fn load(output_dir: &Path) -> anyhow::Result<DownloadState> {
+        let path = output_dir.join(STATE_FILE);
+        if !path.exists() {
+            return Ok(DownloadState::default());
+        }
+        let reader = BufReader::new(File::open(&path)?);
+        Ok(serde_json::from_reader(reader)?)
+    }
+
+    fn save(&self, output_dir: &Path) -> anyhow::Result<()> {
+        let tmp_path = output_dir.join(format!("{STATE_FILE}.tmp"));
+        let writer = BufWriter::new(File::create(&tmp_path)?);
+        serde_json::to_writer_pretty(writer, self)?;
+        std::fs::rename(tmp_path, output_dir.join(STATE_FILE))?;
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DownloadOutcome {
+    Updated,
+    Unchanged,
+    Missing,
+}
+
+#[derive(Debug, Default)]
+pub struct DownloadSummary {
+    pub updated: usize,
+    pub unchanged: usize,
+    pub missing: usize,
+    pub failed: Vec<(String, String)>,
+}
+
+fn is_transient(error: &anyhow::Error) -> bool {
+    match error.downcast_ref::<ureq::Error>() {
+        Some(ureq::Error::Status(status, _)) => *status == 429 || *status >= 500,
+        Some(ureq::Error::Transport(_)) => true,
+        // Connections that drop while reading the body.
+        None => error.downcast_ref::<std::io::Error>().is_some(),
+    }
+}
+
+pub fn sha256_file(path: &Path) -> anyhow::Result<(u64, String)> {
+    let mut hasher = Sha256::new();
+    let size = std::io::copy(&mut BufReader::new(File::open(path)?), &mut hasher)?;
+    Ok((size, format!("{:x}", hasher.finalize())))
+}
+
+/// Downloads files into a directory with conditional requests, so that files that haven't
+/// changed aren't downloaded again. Files are written to a `.part` file first, which is resumed
+/// after a failure and renamed into place once complete.
+pub struct Downloader {
+    client: HttpClient,
+    output_dir: PathBuf,
+    retries: usize,
+    state: Mutex<DownloadState>,
+}
+
+impl Downloader {
+    pub fn new(output_dir: &Path, retries: usize) -> anyhow::Result<Self> {
+        std::fs::create_dir_all(output_dir)?;
+        Ok(Downloader {
+            client: github::get_client()?,
+            output_dir: output_dir.to_path_buf(),
+            retries,
+            state: Mutex::new(DownloadState::load(output_dir)?),
+        })
+    }
+
+    fn update_state(&self, update: impl FnOnce(&mut DownloadState)) -> anyhow::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        update(&mut state);
+        state.save(&self.output_dir)
+    }
+
+    /// Downloads `url` to `path`, relative to the output directory, retrying transient errors.
+    pub fn download(&self, url: &str, path: &str) -> anyhow::Result<DownloadOutcome> {
+        let mut attempt = 0;
+        loop {
+            match self.try_download(url, path) {
+                Ok(outcome) => return Ok(outcome),
+                Err(e) if attempt < self.retries && is_transient(&e) => {
+                    attempt += 1;
+                    sleep(Duration::from_secs(3 * attempt as u64));
+                }
+                Err(e) => return Err(e.context(format!("Downloading {url}"))),
+            }
+        }
+    }
+
+    fn try_download(&self, url: &str, path: &str) -> anyhow::Result<DownloadOutcome> {
+        let target = self.output_dir.join(path);
+        let part = self.output_dir.join(format!("{path}.part"));
+        let (current, partial) = {
+            let state = self.state.lock().unwrap();
+            let current = state
+                .files
+                .get(path)
+                .filter(|_| target.exists())
+                .map(|f| (f.etag.clone(), f.last_modified.clone()));
+            (current, state.partial.get(path).cloned())
+        };
+
+        let mut request = self.client.get(url).set("Accept-Encoding", "identity");
+        if let Some((etag, last_modified)) = &current {
+            if let Some(etag) = etag {
+                request = request.set("If-None-Match", etag);
+            }
+            if let Some(last_modified) = last_modified {
+                request = request.set("If-Modified-Since", last_modified);
+            }
+        }
+        let resume = part.metadata().map(|m| m.len()).unwrap_or_default();
+        let resume = match partial.as_ref().and_then(PartialFile::if_range) {
+            Some(validator) if resume > 0 => {
+                request = request
+                    .set("Range", &format!("bytes={resume}-"))
+                    .set("If-Range", validator);
+                resume
+            }
+            _ => 0,
+        };
+
+        let response = match request.call() {
+            Ok(response) => response,
+            Err(ureq::Error::Status(404, _)) => return Ok(DownloadOutcome::Missing),
+            Err(ureq::Error::Status(416, _)) if resume > 0 => {
+                // The partial file doesn't match the remote one, start again.
+                std::fs::remove_file(&part)?;
+                self.update_state(|state| {
+                    state.partial.remove(path);
+                })?;
+                return self.try_download(url, path);
+            }
+            Err(e) => return Err(e.into()),
+        };
+        let validators = PartialFile {
+            etag: response.header("ETag").map(str::to_string),
+            last_modified: response.header("Last-Modified").map(str::to_string),
+        };
+        let file = match response.status() {
+            304 => return Ok(DownloadOutcome::Unchanged),
+            206 => {
+                let expected = format!("bytes {resume}-");
+                if !response
+                    .header("Content-Range")
+                    .is_some_and(|range| range.starts_with(&expected))
+                {
+                    std::fs::remove_file(&part)?;
+                    bail!("Unexpected range in the response, expected {expected}");
+                }
+                OpenOptions::new().append(true).open(&part)?
+            }
+            _ => {
+                if let Some(parent) = part.parent() {
+                    std::fs::create_dir_all(parent)?;
+                }
+                self.update_state(|state| {
+                    state.partial.insert(path.to_string(), validators.clone());
+                })?;
+                File::create(&part)?
+            }
+        };
+        let mut writer = BufWriter::new(file);
+        std::io::copy(&mut response.into_reader(), &mut writer)?;
+        writer.flush()?;
+
+        let (size, sha256) = sha256_file(&part)?;
+        std::fs::rename(&part, &target)
+            .with_context(|| format!("Moving {} into place", part.display()))?;
+        let validators = match partial {
+            Some(partial) if resume > 0 => partial,
+            _ => validators,
+        };
+        self.update_state(|state| {
+            state.partial.remove(path);
+            state.files.insert(
+                path.to_string(),
+                DownloadedFile {
+                    url: url.to_string(),
+                    etag: validators.etag,
+                    last_modified: validators.last_modified,
+                    size,
+                    sha256,
+                    downloaded_at: Utc::now(),
+                },
+            );
+        })?;
+        Ok(DownloadOutcome::Updated)
+    }
+
+    fn download_repository(
+        &self,
+        repo: &DataRepo,
+        assets: &[Asset],
+        github_token: &str,
+    ) -> anyhow::Result<DownloadOutcome> {
+        let mut outcomes = vec![];
+        for asset in assets {
+            match asset {
+                Asset::Dataset => outcomes.push(self.download(
+                    &format!(
+                        "https://github.com/pypi-data/{}/releases/download/latest/dataset.parquet",
+                        repo.name
+                    ),
+                    &format!("{}.parquet", repo.name),
+                )?),
+                Asset::Index => outcomes.push(self.download(
+                    &format!(
+                        "https://raw.githubusercontent.com/pypi-data/{}/main/index.json",
+                        repo.name
+                    ),
+                    &format!("indexes/{}.json", repo.name),
+                )?),
+                Asset::Batches => {
+                    let release_assets = github::releases::get_release_assets(
+                        Some(self.client.clone()),
+                        github_token,
+                        &repo.name,
+                        "latest",
+                    )?
+                    .unwrap_or_default();
+                    for release_asset in release_assets
+                        .iter()
+                        .filter(|a| a.name.starts_with("index-") && a.name.ends_with(".parquet"))
+                    {
+                        outcomes.push(self.download(
+                            &release_asset.browser_download_url,
+                            &format!("batches/{}/{}", repo.name, release_asset.name),
+                        )?);
+                    }
+                }
+            }
+        }
+        Ok(if outcomes.contains(&DownloadOutcome::Updated) {
+            DownloadOutcome::Updated
+        } else if outcomes.contains(&DownloadOutcome::Unchanged) {
+            DownloadOutcome::Unchanged
+        } else {
+            DownloadOutcome::Missing
+        })
+    }
+
+    /// Downloads the assets of every repository, `jobs` at a time. A repository counts as updated
+    /// if any of its files changed, and as missing if none of them exist.
+    pub fn download_all(
+        &self,
+        repos: &[DataRepo],
+        assets: &[Asset],
+        github_token: &str,
+        jobs: usize,
+    ) -> anyhow::Result<DownloadSummary> {
+        let pool = rayon::ThreadPoolBuilder::new().num_threads(jobs).build()?;
+        let results = pool.install(|| {
+            repos
+                .par_iter()
+                .progress()
+                .map(|repo| self.download_repository(repo, assets, github_token))
+                .collect::<Vec<_>>()
+        });
+        let mut summary = DownloadSummary::default();
+        for (repo, result) in repos.iter().zip(results) {
+            match result {
+                Ok(DownloadOutcome::Updated) => summary.updated += 1,
+                Ok(DownloadOutcome::Unchanged) => summary.unchanged += 1,
+                Ok(DownloadOutcome::Missing) => summary.missing += 1,
+                Err(e) => summary.failed.push((repo.name.clone(), format!("{e:#}"))),
+            }
+        }
+        Ok(summary)
+    }
+}
@@ -0,0 +1,232 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Component, Path, PathBuf};
+
+use anyhow::{bail, Context};
+use git2::{Oid, Repository};
+use polars::prelude::*;
+
+use crate::github;
+use crate::github::create::REPO_CODE_PREFIX;
+use crate::query::project_filter;
+
+/// Which files of the dataset to fetch. At least one of these must be given.
+#[derive(Debug, Default, clap::Args)]
+pub struct FileSelector {
+    /// Project name
+    #[clap(long)]
+    pub project: Option<String>,
+
+    /// Release filename, e.g. requests-2.31.0-py3-none-any.whl
+    #[clap(long)]
+    pub release: Option<String>,
+
+    /// Path of the file inside the release, or its full path in the repository
+    #[clap(long)]
+    pub path: Option<String>,
+
+    /// Hash of the file, in hex
+    #[clap(long)]
+    pub hash: Option<String>,
+}
+
+impl FileSelector {
+    fn filter(&self) -> anyhow::Result<Expr> {
+        let mut filters = vec![];
+        if let Some(project) = &self.project {
+            filters.push(project_filter(project));
+        }
+        if let Some(release) = &self.release {
+            filters.push(col("project_release").eq(lit(release.as_str())));
+        }
+        if let Some(path) = &self.path {
+            let path = lit(path.as_str());
+            filters.push(
+                col("path")
+                    .eq(path.clone())
+                    .or(col("archive_path").eq(path)),
+            );
+        }
+        if let Some(hash) = &self.hash {
+            if hash.len() != 40 {
+                bail!("Expected a 40 character hash, found {hash}");
+            }
+            let hash = Oid::from_str(hash)?;
+            filters.push(col("hash").eq(lit(hash.as_bytes().to_vec())));
+        }
+        let Some(filter) = filters.into_iter().reduce(Expr::and) else {
+            bail!("Give at least one of --project, --release, --path or --hash");
+        };
+        Ok(filter)
+    }
+}
+
+/// A file in one of the repositories.
+#[derive(Debug)]
+pub struct BlobLocation {
+    pub repository: String,
+    /// Path on the `code` branch of the repository.
+    pub path: String,
+    pub hash: Oid,
+    /// Files that were skipped, e.g. for being binary, are indexed but not stored.
+    pub skip_reason: Option<String>,
+}
+
+/// Finds the files matching the selector in the dataset, in a stable order.
+pub fn resolve(
+    mut dataset: LazyFrame,
+    selector: &FileSelector,
+) -> anyhow::Result<Vec<BlobLocation>> {
+    if !dataset.collect_schema()?.contains("repository") {
+        bail!("The dataset doesn't record which repository files are in. Use a merged dataset");
+    }
+    let df = dataset
+        .filter(selector.filter()?)
+        .select([
+            col("repository").cast(DataType::UInt32),
+            col("path"),
+            col("hash"),
+            col("skip_reason").cast(DataType::String),
+        ])
+        .sort(["repository", "path"], Default::default())
+        .collect()?;
+    let repositories = df.column("repository")?.u32()?;
+    let paths = df.column("path")?.str()?;
+    let hashes = df.column("hash")?.binary()?;
+    let skip_reasons = df.column("skip_reason")?.str()?;
+    (0..df.height())
+        .map(|idx| {
+            let (Some(repository), Some(path), Some(hash)) =
+                (repositories.get(idx), paths.get(idx), hashes.get(idx))
+            else {
+                bail!("Incomplete row {idx} in the dataset");
+            };
+            Ok(BlobLocation {
+                repository: format!("{REPO_CODE_PREFIX}{repository}"),
+                path: path.to_string(),
+                hash: Oid::from_bytes(hash)?,
+                skip_reason: skip_reasons.get(idx).map(str::to_string),
+            })
+        })
+        .collect()
+}
+
+/// Fetches blobs from local clones of the repositories when they have them, and from the GitHub
+/// API otherwise.
+pub struct BlobFetcher {
+    repositories_dir: Option<PathBuf>,
+    github_token: Option<String>,
+    opened: HashMap<String, Option<Repository>>,
+}
+
+impl BlobFetcher {
+    /// `repositories_dir` holds clones of the repositories, named after them.
+    pub fn new(repositories_dir: Option<PathBuf>, github_token: Option<String>) -> Self {
+        BlobFetcher {
+            repositories_dir,
+            github_token,
+            opened: HashMap::new(),
+        }
+    }
+
+    fn local_repository(&mut self, name: &str) -> Option<&Repository> {
+        let repositories_dir = self.repositories_dir.as_ref()?;
+        self.opened
+            .entry(name.to_string())
+            .or_insert_with(|| Repository::open(repositories_dir.join(name)).ok())
+            .as_ref()
+    }
+
+    /// Writes the contents of the file to `writer`.
+    pub fn fetch(
+        &mut self,
+        location: &BlobLocation,
+        writer: &mut impl Write,
+    ) -> anyhow::Result<()> {
+        if let Some(reason) = &location.skip_reason {
+            bail!(
+                "{} is not stored in the repository: {reason}",
+                location.path
+            );
+        }
+        if let Some(repo) = self.local_repository(&location.repository) {
+            if let Ok(blob) = repo.find_blob(location.hash) {
+                writer.write_all(blob.content())?;
+                return Ok(());
+            }
+        }
+        let mut reader = github::blobs::get_blob(
+            None,
+            self.github_token.as_deref(),
+            &location.repository,
+            &location.hash.to_string(),
+        )
+        .with_context(|| format!("Fetching {} from {}", location.path, location.repository))?;
+        std::io::copy(&mut reader, writer)?;
+        Ok(())
+    }
+}
+
+/// Writes a single file to stdout. Copies of the same contents are fine, anything else needs a
+/// narrower selector.
+pub fn cat(locations: &[BlobLocation], fetcher: &mut BlobFetcher) -> anyhow::Result<()> {
+    let Some(first) = locations.first() else {
+        bail!("No matching files");
+    };
+    if locations.iter().any(|l| l.hash != first.hash) {
+        bail!(
+            "{} files with different contents match, e.g. {} and {}. Use export for several files",
+            locations.len(),
+            first.path,
+            locations
+                .iter()
+                .find(|l| l.hash != first.hash)
+                .unwrap()
+                .path
+        );
+    }
+    let location = locations
+        .iter()
+        .find(|l| l.skip_reason.is_none())
+        .unwrap_or(first);
+    let mut stdout = BufWriter::new(std::io::stdout().lock());
+    fetcher.fetch(location, &mut stdout)?;
+    stdout.flush()?;
+    Ok(())
+}
+
+/// Writes the files under `output_dir`, at their path in the repository. Files that are not
+/// stored in the repositories are reported and skipped. Returns the number of files written.
+pub fn export(
+    locations: &[BlobLocation],
+    fetcher: &mut BlobFetcher,
+    output_dir: &Path,
+) -> anyhow::Result<usize> {
+    if locations.is_empty() {
+        bail!("No matching files");
+    }
+    let mut written = 0;
+    for location in locations {
+        if let Some(reason) = &location.skip_reason {
+            eprintln!("Skipping {}: {reason}", location.path);
+            continue;
+        }
+        let path = Path::new(&location.path);
+        if !path.components().all(|c| matches!(c, Component::Normal(_))) {
+            bail!(
+                "Refusing to write outside the output directory: {}",
+                location.path
+            );
+        }
+        let output_path = output_dir.join(path);
+        if let Some(parent) = output_path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let mut writer = BufWriter::new(File::create(&output_path)?);
+        fetcher.fetch(location, &mut writer)?;
+        writer.flush()?;
+        written += 1;
+    }
+    Ok(written)
+}
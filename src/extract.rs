@@ -1,25 +1,30 @@
-use crate::archive::tar::{iter_tar_bz_contents, iter_tar_gz_contents};
+use crate::analysis::{AnalysisWriter, Analyzer};
+use crate::archive::content::{PathMapper, SkipReason};
+use crate::archive::tar::{iter_tar_bz_contents, iter_tar_gz_contents, tar_paths};
 use crate::archive::{ArchiveItem, ArchiveType, ExtractionError};
-use crate::data::{IndexItem, PackageFileIndex, RepositoryFileIndexWriter};
-use crate::git::GitFastImporter;
+use crate::data::{companion_path, IndexItem, PackageFileIndex, RepositoryFileIndexWriter};
+use crate::metadata::{MetadataCollector, PackageMetadataWriter};
+use crate::sink::OutputSink;
+use crate::worker::{WorkerLimits, WorkerPool};
 
 use crate::archive::zip::iter_zip_contents;
+use crate::http::HttpClient;
 use crate::repository::package::RepositoryPackage;
 use anyhow::Result;
 use bzip2::read::BzDecoder;
 use flate2::read::GzDecoder;
 use indicatif::ParallelProgressIterator;
 use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
 use std::ffi::OsStr;
-use std::io::{BufWriter, Read, Stdout, Write};
+use std::io::Read;
 use std::path::{Path, PathBuf};
 use std::sync::Mutex;
-use std::time::Duration;
 use std::{io, panic};
 use tar::Archive;
 use thiserror::Error;
 use tracing::{event, span, Level};
-use ureq::{Agent, Error, Transport};
+use ureq::{Error, Transport};
 
 #[derive(Error, Debug)]
 pub enum DownloadError {
@@ -49,23 +54,87 @@ pub enum DownloadError {
 
     #[error("Zip Error: {0}")]
     ZipError(#[from] zip::result::ZipError),
+
+    #[error("Worker Error: {0}")]
+    WorkerError(String),
+
+    #[error("HTTP client error: {0:#}")]
+    ClientError(anyhow::Error),
+
+    #[error("There was an error writing the index: {0:#}")]
+    IndexError(#[from] anyhow::Error),
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub enum PathLayout {
+    /// Every release lives in its own directory: `packages/<name>/<filename>/`.
+    #[default]
+    Packages,
+    /// Releases of a project replace each other at `projects/<name>/<kind>/`, with the
+    /// top-level sdist directory stripped. Wheels are kept apart by their tags.
+    Projects,
+}
+
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize)]
+pub struct ExtractOptions {
+    pub layout: PathLayout,
+    pub normalize_paths: bool,
+    /// Keep the entries read before an archive turned out to be corrupt, rather than failing
+    /// the whole package.
+    pub tolerant: bool,
+    /// Leave files containing secrets out of the output, recording them as skipped.
+    pub exclude_secrets: bool,
+    /// Extract every package in a separate worker process under these limits.
+    pub isolation: Option<WorkerLimits>,
+}
+
+impl ExtractOptions {
+    fn path_mapper(&self, package: &RepositoryPackage) -> PathMapper {
+        match self.layout {
+            PathLayout::Packages => PathMapper::new(
+                package.file_prefix(),
+                package.archive_root(),
+                self.normalize_paths,
+            ),
+            PathLayout::Projects => {
+                PathMapper::new(package.project_prefix(), package.archive_root(), true)
+            }
+        }
+    }
+
+    /// The directory the contents of a package are written to.
+    pub fn output_prefix(&self, package: &RepositoryPackage) -> String {
+        self.path_mapper(package).map("")
+    }
 }
 
-pub fn download_packages(
+pub fn download_packages<S: OutputSink + Send>(
     packages: Vec<RepositoryPackage>,
     index_file: PathBuf,
-    output: Mutex<GitFastImporter<BufWriter<Stdout>>>,
+    output: Mutex<S>,
+    options: ExtractOptions,
 ) -> Result<Vec<RepositoryPackage>, DownloadError> {
     let total = packages.len() as u64;
 
     let _span = span!(Level::INFO, "started_downloading_packages", total = total).entered();
     let index_writer = Mutex::new(RepositoryFileIndexWriter::new(&index_file));
+    let metadata_writer = Mutex::new(PackageMetadataWriter::new(&companion_path(
+        &index_file,
+        "metadata",
+    )));
+    let analysis_writer = Mutex::new(AnalysisWriter::new(&index_file));
+    // Workers build their own client from the same options, so a bad configuration is reported
+    // once here rather than for every package.
+    let client = crate::http::client().map_err(DownloadError::ClientError)?;
+    let workers = options.isolation.map(WorkerPool::new);
 
     event!(Level::INFO, "starting par_iter");
+    // A package that fails is left unprocessed for the next run, but failing to write the index
+    // or the other datasets would leave committed packages without rows, so it stops the run.
     let processed_packages: Vec<_> = packages
         .into_par_iter()
         .progress_count(total)
-        .flat_map(|package| {
+        .map(|mut package| {
             let _span = span!(
                 Level::INFO,
                 "downloading_package",
@@ -73,61 +142,93 @@ pub fn download_packages(
             )
             .entered();
             event!(Level::INFO, "download started");
-            let panic: Result<_, _> = panic::catch_unwind(|| {
-                let agent = ureq::agent();
-                download_package(agent, &package, &output)
-            });
-            event!(Level::INFO, "extraction finished");
-            let result = match panic {
-                Ok(r) => r,
-                Err(err) => {
-                    event!(Level::ERROR, "download_package panicked");
-                    if let Some(s) = err.downcast_ref::<String>() {
-                        println!("download_package panicked {s}");
-                        return Err(DownloadError::PanicError(s.clone()));
-                    } else if let Some(s) = err.downcast_ref::<&str>() {
-                        println!("download_package panicked {s}");
-                        return Err(DownloadError::PanicError(s.to_string()));
-                    } else {
-                        event!(
-                            Level::ERROR,
-                            "Unknown download_package panic, resuming unwind"
-                        );
-                        println!("Unknown panic type: {:?}", err.type_id());
-                        panic::resume_unwind(err);
+            let result = if let Some(workers) = &workers {
+                workers
+                    .download_package(&package, &output, options)
+                    .inspect_err(|e| {
+                        if !matches!(e, DownloadError::Missing | DownloadError::SkippedFormat) {
+                            event!(Level::WARN, "{} failed: {e}", package.identifier());
+                        }
+                    })
+            } else {
+                // The client only holds its connection pool, which is still usable after a panic.
+                let panic: Result<_, _> = panic::catch_unwind(panic::AssertUnwindSafe(|| {
+                    download_package(client.clone(), &package, &output, options)
+                }));
+                match panic {
+                    Ok(r) => r,
+                    Err(err) => {
+                        event!(Level::ERROR, "download_package panicked");
+                        if let Some(s) = err.downcast_ref::<String>() {
+                            println!("download_package panicked {s}");
+                            Err(DownloadError::PanicError(s.clone()))
+                        } else if let Some(s) = err.downcast_ref::<&str>() {
+                            println!("download_package panicked {s}");
+                            Err(DownloadError::PanicError(s.to_string()))
+                        } else {
+                            event!(
+                                Level::ERROR,
+                                "Unknown download_package panic, resuming unwind"
+                            );
+                            println!("Unknown panic type: {:?}", err.type_id());
+                            panic::resume_unwind(err);
+                        }
                     }
                 }
             };
-            let index_items = match result {
+            event!(Level::INFO, "extraction finished");
+            let mut index_items = match result {
                 Ok(idx) => idx,
                 Err(e) => {
+                    output.lock().unwrap().skip_package(&package)?;
                     return match e {
-                        DownloadError::Missing | DownloadError::SkippedFormat => Ok(package),
-                        _ => Err(e),
+                        DownloadError::Missing | DownloadError::SkippedFormat => Ok(Some(package)),
+                        _ => Ok(None),
                     };
                 }
             };
+            if let Some(metadata) = index_items.metadata.take() {
+                metadata_writer
+                    .lock()
+                    .unwrap()
+                    .write_metadata(&package, metadata)?;
+            }
+            let analysis = std::mem::take(&mut index_items.analysis);
+            analysis_writer
+                .lock()
+                .unwrap()
+                .write_analysis(&package, analysis)?;
+            let truncated = index_items.truncated.clone();
             event!(Level::DEBUG, "writing index");
-            index_writer.lock().unwrap().write_index(index_items);
+            index_writer.lock().unwrap().write_index(index_items)?;
             event!(Level::DEBUG, "index written");
-            Ok(package)
+            package.truncated = truncated;
+            Ok(Some(package))
         })
+        .collect::<Result<Vec<_>, DownloadError>>()
+        .inspect_err(|e| event!(Level::ERROR, "Stopping extraction: {e}"))?
+        .into_iter()
+        .flatten()
         .collect();
 
     event!(Level::INFO, "Finishing output");
     output.lock().unwrap().finish()?;
-    index_writer.into_inner().unwrap().finish().unwrap();
+    index_writer.into_inner().unwrap().finish()?;
+    metadata_writer.into_inner().unwrap().finish()?;
+    analysis_writer.into_inner().unwrap().finish()?;
     Ok(processed_packages)
 }
 
 fn write_package_contents<
+    'a,
     T: Iterator<Item = Result<(IndexItem, Option<ArchiveItem>), ExtractionError>>,
-    O: Write,
+    S: OutputSink,
 >(
-    package: &RepositoryPackage,
+    package: &'a RepositoryPackage,
     mut contents: T,
-    output: &Mutex<GitFastImporter<O>>,
-) -> Result<Vec<IndexItem>, ExtractionError> {
+    output: &Mutex<S>,
+    options: ExtractOptions,
+) -> Result<PackageFileIndex<'a>, ExtractionError> {
     let _span = span!(
         Level::INFO,
         "writing_package_contents",
@@ -137,16 +238,41 @@ fn write_package_contents<
     let mut path_to_nodes = vec![];
     let mut index_items = vec![];
     let mut error = None;
+    let mut truncated = None;
+    let mut metadata = MetadataCollector::default();
+    let mut analyzer = Analyzer::new();
 
     for result in contents.by_ref() {
-        let (index_item, item) = match result {
+        let (mut index_item, item) = match result {
             Ok(v) => v,
+            Err(e) if options.tolerant => {
+                event!(
+                    Level::WARN,
+                    "Archive truncated after {} items: {:?}",
+                    index_items.len(),
+                    e
+                );
+                truncated = Some(e.to_string());
+                break;
+            }
             Err(e) => {
                 error = Some(e);
                 break;
             }
         };
         if let Some(item) = item {
+            metadata.add(&index_item.normalized_path, &item.data);
+            // Skipped files only come with their contents for the metadata.
+            if index_item.skip_reason.is_some() {
+                index_items.push(index_item);
+                continue;
+            }
+            let has_secrets = analyzer.add(&item.path, &item.data);
+            if has_secrets && options.exclude_secrets {
+                index_item.skip_reason = Some(SkipReason::Secret);
+                index_items.push(index_item);
+                continue;
+            }
             let node = match output.lock().unwrap().add_file(item.data) {
                 Ok(v) => v,
                 Err(e) => {
@@ -167,26 +293,41 @@ fn write_package_contents<
         );
         // let left_items = contents.count();
         // event!(Level::ERROR, "Skipped {} items due to error", left_items);
+        let nodes = path_to_nodes.into_iter().map(|(node, _)| node).collect();
+        if let Err(discard_error) = output.lock().unwrap().discard(package, nodes) {
+            event!(
+                Level::ERROR,
+                "Error discarding package contents: {discard_error}"
+            );
+        }
         return Err(e);
     }
 
     event!(Level::INFO, "Flushing commit");
-    output
+    let commit_package = output
         .lock()
         .unwrap()
-        .flush_commit(&package.identifier(), path_to_nodes)?;
+        .flush_commit(package, path_to_nodes)?;
     event!(Level::INFO, "Commit flushed");
-    Ok(index_items)
+    let mut index = PackageFileIndex::new(
+        package,
+        index_items,
+        truncated,
+        metadata.finish(),
+        analyzer.finish(),
+    );
+    index.commit_package = commit_package;
+    Ok(index)
 }
 
-pub fn download_package<'a, O: Write>(
-    agent: Agent,
+pub fn download_package<'a, S: OutputSink>(
+    client: HttpClient,
     package: &'a RepositoryPackage,
-    output: &Mutex<GitFastImporter<O>>,
+    output: &Mutex<S>,
+    options: ExtractOptions,
 ) -> Result<PackageFileIndex<'a>, DownloadError> {
-    let resp = agent
+    let resp = client
         .request_url("GET", &package.url)
-        .timeout(Duration::from_secs(30))
         .call()
         .map_err(|e| match e {
             Error::Status(404, _) => DownloadError::Missing,
@@ -211,29 +352,45 @@ pub fn download_package<'a, O: Write>(
         .map_err(|_| DownloadError::UnknownArchive(extension.to_string()))?;
 
     let reader = contents.as_slice();
+    let mut paths = options.path_mapper(package);
 
-    let items = match archive_type {
+    // The top-level directory of an archive doesn't always match its filename, so it is found
+    // from the entries before extracting. Tar archives need an extra pass over the headers, which
+    // is only worth it when the root is stripped from the output paths.
+    let package_index = match archive_type {
         ArchiveType::Zip => {
             let mut archive = zip::ZipArchive::new(std::io::Cursor::new(reader))?;
-            let iterator = iter_zip_contents(&mut archive, package.file_prefix())?;
-            write_package_contents(package, iterator, output)?
+            paths.detect_root(archive.file_names());
+            let iterator = iter_zip_contents(&mut archive, paths)?;
+            write_package_contents(package, iterator, output, options)?
         }
         ArchiveType::TarGz => {
+            if paths.strips_root() {
+                let names = tar_paths(&mut Archive::new(GzDecoder::new(reader)));
+                paths.detect_root(names.iter().map(String::as_str));
+            } else {
+                paths.detect_root_from_first_entry();
+            }
             let tar = GzDecoder::new(reader);
             let mut archive = Archive::new(tar);
-            let iterator = iter_tar_gz_contents(&mut archive, package.file_prefix())?;
-            write_package_contents(package, iterator, output)?
+            let iterator = iter_tar_gz_contents(&mut archive, paths, options.tolerant)?;
+            write_package_contents(package, iterator, output, options)?
         }
         ArchiveType::TarBz => {
+            if paths.strips_root() {
+                let names = tar_paths(&mut Archive::new(BzDecoder::new(reader)));
+                paths.detect_root(names.iter().map(String::as_str));
+            } else {
+                paths.detect_root_from_first_entry();
+            }
             let tar = BzDecoder::new(reader);
             let mut archive = Archive::new(tar);
-            let iterator = iter_tar_bz_contents(&mut archive, package.file_prefix())?;
-            write_package_contents(package, iterator, output)?
+            let iterator = iter_tar_bz_contents(&mut archive, paths, options.tolerant)?;
+            write_package_contents(package, iterator, output, options)?
         }
         ArchiveType::Exe => {
             return Err(DownloadError::SkippedFormat);
         }
     };
-    let package_index = PackageFileIndex::new(package, items);
     Ok(package_index)
 }
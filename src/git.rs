@@ -1,8 +1,65 @@
+use crate::repository::package::RepositoryPackage;
+use crate::sink::OutputSink;
 use chrono::prelude::*;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::io;
 use std::io::Write;
+use std::str::FromStr;
 use std::sync::Mutex;
 
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CommitGrouping {
+    Package,
+    Count(usize),
+    Project,
+    Day,
+}
+
+impl FromStr for CommitGrouping {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "package" => Ok(CommitGrouping::Package),
+            "project" => Ok(CommitGrouping::Project),
+            "day" => Ok(CommitGrouping::Day),
+            _ => match s.strip_prefix("count:").map(str::parse) {
+                Some(Ok(count)) if count > 0 => Ok(CommitGrouping::Count(count)),
+                _ => Err(format!(
+                    "Unknown commit grouping {s}. Expected package, project, day or count:N"
+                )),
+            },
+        }
+    }
+}
+
+impl CommitGrouping {
+    fn key(&self, package: &RepositoryPackage) -> Option<String> {
+        match self {
+            CommitGrouping::Package => None,
+            CommitGrouping::Count(_) => Some(String::new()),
+            CommitGrouping::Project => Some(package.project_name.clone()),
+            CommitGrouping::Day => Some(package.upload_time.format("%Y-%m-%d").to_string()),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct PendingCommit {
+    // The first package, named in the subject so that the index can point to the commit.
+    first_package: String,
+    packages: Vec<String>,
+    paths_to_nodes: Vec<(usize, String)>,
+}
+
+#[derive(Debug)]
+struct PendingRelease {
+    upload_time: DateTime<Utc>,
+    identifier: String,
+    prefix: String,
+    paths_to_nodes: Vec<(usize, String)>,
+}
+
 #[derive(Debug)]
 pub struct GitFastImporter<T: Write> {
     output: T,
@@ -11,43 +68,114 @@ pub struct GitFastImporter<T: Write> {
     branch: String,
     should_use_from: bool,
     total: usize,
-    commit_count: usize,
+    package_count: usize,
     skip_contents: bool,
+    grouping: CommitGrouping,
+    pending: BTreeMap<String, PendingCommit>,
+    // In project history mode every project gets its own `projects/<name>` branch. This holds the
+    // branches that already existed before this run.
+    project_branches: Option<HashSet<String>>,
+    // Project branches written earlier in this stream, which fast-import holds in memory.
+    created_branches: HashSet<String>,
+    pending_releases: BTreeMap<String, Vec<PendingRelease>>,
+    // Packages still to come for every pending key, so that groups are written as soon as they
+    // are complete rather than held until the end.
+    remaining: HashMap<String, usize>,
+    seen: HashSet<String>,
 }
 
 impl<T: Write> GitFastImporter<T> {
     pub fn new(
         output: T,
-        total: usize,
+        packages: &[RepositoryPackage],
         branch: String,
         has_code_branch: bool,
         skip_contents: bool,
+        grouping: CommitGrouping,
+        project_branches: Option<HashSet<String>>,
     ) -> Mutex<Self> {
-        Mutex::new(GitFastImporter {
+        let mut importer = GitFastImporter {
             output,
             current_mark: 0,
             previous_commit_mark: None,
             should_use_from: has_code_branch,
-            commit_count: 0,
-            total,
+            package_count: 0,
+            total: packages.len(),
             branch,
             skip_contents,
-        })
+            grouping,
+            pending: BTreeMap::new(),
+            project_branches,
+            created_branches: HashSet::new(),
+            pending_releases: BTreeMap::new(),
+            remaining: HashMap::new(),
+            seen: HashSet::new(),
+        };
+        for package in packages {
+            if let Some(key) = importer.pending_key(package) {
+                *importer.remaining.entry(key).or_default() += 1;
+            }
+        }
+        Mutex::new(importer)
     }
 
-    pub fn finish(&mut self) -> io::Result<()> {
-        writeln!(self.output, "done")?;
+    /// The key packages are held under until their commit is written, if they aren't committed
+    /// straight away.
+    fn pending_key(&self, package: &RepositoryPackage) -> Option<String> {
+        match self.project_branches {
+            Some(_) => Some(package.normalized_project_name()),
+            None => self.grouping.key(package),
+        }
+    }
+
+    /// Counts a package as done, writing the commits of its key once no more packages can
+    /// arrive for it.
+    fn package_done(&mut self, package: &RepositoryPackage) -> io::Result<()> {
+        let Some(key) = self.pending_key(package) else {
+            return Ok(());
+        };
+        if !self.seen.insert(package.identifier()) {
+            return Ok(());
+        }
+        let Some(remaining) = self.remaining.get_mut(&key) else {
+            return Ok(());
+        };
+        *remaining = remaining.saturating_sub(1);
+        if *remaining > 0 {
+            return Ok(());
+        }
+        self.remaining.remove(&key);
+        if let Some(pending) = self.pending.remove(&key) {
+            self.write_pending_commit(pending)?;
+        }
+        if let Some(releases) = self.pending_releases.remove(&key) {
+            self.write_project_history(&key, releases)?;
+        }
+        Ok(())
+    }
+
+    fn write_commit_header(&mut self, commit_message: &str) -> io::Result<()> {
+        let parent = match self.previous_commit_mark {
+            Some(previous_mark) => Some(format!(":{previous_mark}")),
+            None if self.should_use_from => Some(self.branch.clone()),
+            None => None,
+        };
+        self.should_use_from = false;
+        let branch = self.branch.clone();
+        self.write_branch_commit_header(&branch, parent, commit_message)?;
+        self.previous_commit_mark = Some(self.current_mark);
         Ok(())
     }
 
-    pub fn flush_commit(
+    fn write_branch_commit_header(
         &mut self,
-        name: &str,
-        paths_to_nodes: Vec<(usize, String)>,
+        branch: &str,
+        parent: Option<String>,
+        commit_message: &str,
     ) -> io::Result<()> {
         self.current_mark += 1;
         let now = Utc::now();
-        writeln!(self.output, "commit refs/heads/{}", self.branch)?;
+        writeln!(self.output, "commit refs/heads/{branch}")?;
         writeln!(self.output, "mark :{}", self.current_mark)?;
         writeln!(
             self.output,
@@ -55,21 +183,27 @@ impl<T: Write> GitFastImporter<T> {
             now.timestamp()
         )?;
 
-        let commit_message = format!("Add package {name}");
         writeln!(self.output, "data {}", commit_message.len())?;
         writeln!(self.output, "{commit_message}")?;
 
-        if self.should_use_from {
-            writeln!(self.output, "from {}", self.branch)?;
-            self.should_use_from = false;
-        }
-
-        if let Some(previous_mark) = self.previous_commit_mark {
-            writeln!(self.output, "from :{previous_mark}")?;
+        if let Some(parent) = parent {
+            writeln!(self.output, "from {parent}")?;
         }
+        Ok(())
+    }
 
-        self.previous_commit_mark = Some(self.current_mark);
+    fn write_commit(
+        &mut self,
+        commit_message: &str,
+        package_count: usize,
+        paths_to_nodes: Vec<(usize, String)>,
+    ) -> io::Result<()> {
+        self.write_commit_header(commit_message)?;
+        self.write_file_modifications(paths_to_nodes)?;
+        self.write_progress(package_count)
+    }
 
+    fn write_file_modifications(&mut self, paths_to_nodes: Vec<(usize, String)>) -> io::Result<()> {
         for (mark, path) in paths_to_nodes {
             if path.is_empty() {
                 continue;
@@ -77,20 +211,194 @@ impl<T: Write> GitFastImporter<T> {
             writeln!(self.output, "M 100644 :{mark} {path}")?;
         }
         writeln!(self.output)?;
-        self.commit_count += 1;
-        if self.commit_count.is_multiple_of(10) {
+        Ok(())
+    }
+
+    fn write_progress(&mut self, package_count: usize) -> io::Result<()> {
+        let previous_count = self.package_count;
+        self.package_count += package_count;
+        if previous_count / 10 != self.package_count / 10 {
             writeln!(
                 self.output,
                 "progress [{}] Commit: {}/{}",
                 chrono::Local::now().format("%Y-%m-%d %H:%M:%S"),
-                self.commit_count,
+                self.package_count,
                 self.total
             )?;
         }
         Ok(())
     }
 
-    pub fn add_file(&mut self, data: Vec<u8>) -> io::Result<usize> {
+    // Releases arrive interleaved and out of order, so the history of each project is only
+    // written once every release is known.
+    fn write_project_history(
+        &mut self,
+        project: &str,
+        mut releases: Vec<PendingRelease>,
+    ) -> io::Result<()> {
+        releases.sort_by_key(|r| r.upload_time);
+        let branch = format!("projects/{project}");
+        let mut parent = self.project_parent(project);
+        self.created_branches.insert(project.to_string());
+
+        for release in releases {
+            self.write_branch_commit_header(
+                &branch,
+                parent,
+                &format!("Add package {}", release.identifier),
+            )?;
+            parent = Some(format!(":{}", self.current_mark));
+
+            writeln!(self.output, "D {}", release.prefix.trim_end_matches('/'))?;
+            self.write_file_modifications(release.paths_to_nodes)?;
+            self.write_progress(1)?;
+        }
+        Ok(())
+    }
+
+    fn has_project_branch(&self, project: &str) -> bool {
+        self.created_branches.contains(project)
+            || self
+                .project_branches
+                .as_ref()
+                .is_some_and(|b| b.contains(project))
+    }
+
+    // fast-import doesn't allow a branch to start from itself, so branches from a previous run
+    // are continued with `^0`. Branches created in this stream continue without a parent.
+    fn project_parent(&self, project: &str) -> Option<String> {
+        (self.has_project_branch(project) && !self.created_branches.contains(project))
+            .then(|| format!("refs/heads/projects/{project}^0"))
+    }
+
+    // Project paths live on the `projects/<name>` branch of their project rather than the main
+    // branch.
+    fn remove_project_paths(&mut self, name: &str, paths: Vec<String>) -> io::Result<()> {
+        let mut by_project: BTreeMap<String, Vec<String>> = BTreeMap::new();
+        for path in paths {
+            let project = path.split('/').nth(1).unwrap_or_default();
+            if !path.starts_with("projects/") || project.is_empty() {
+                continue;
+            }
+            by_project
+                .entry(project.to_string())
+                .or_default()
+                .push(path);
+        }
+        for (project, paths) in by_project {
+            if !self.has_project_branch(&project) {
+                continue;
+            }
+            let branch = format!("projects/{project}");
+            let parent = self.project_parent(&project);
+            self.write_branch_commit_header(&branch, parent, &format!("Remove package {name}"))?;
+            for path in paths {
+                writeln!(self.output, "D {}", path.trim_end_matches('/'))?;
+            }
+            writeln!(self.output)?;
+        }
+        Ok(())
+    }
+
+    fn write_pending_commit(&mut self, pending: PendingCommit) -> io::Result<()> {
+        let commit_message = format!(
+            "Add {} packages from {}\n\n{}",
+            pending.packages.len(),
+            pending.first_package,
+            pending.packages.join("\n")
+        );
+        self.write_commit(
+            &commit_message,
+            pending.packages.len(),
+            pending.paths_to_nodes,
+        )
+    }
+}
+
+impl<T: Write> OutputSink for GitFastImporter<T> {
+    fn finish(&mut self) -> io::Result<()> {
+        while let Some((_, pending)) = self.pending.pop_first() {
+            self.write_pending_commit(pending)?;
+        }
+        while let Some((project, releases)) = self.pending_releases.pop_first() {
+            self.write_project_history(&project, releases)?;
+        }
+        writeln!(self.output, "done")?;
+        Ok(())
+    }
+
+    fn flush_commit(
+        &mut self,
+        package: &RepositoryPackage,
+        paths_to_nodes: Vec<(usize, String)>,
+    ) -> io::Result<Option<String>> {
+        if self.project_branches.is_some() {
+            self.pending_releases
+                .entry(package.normalized_project_name())
+                .or_default()
+                .push(PendingRelease {
+                    upload_time: package.upload_time,
+                    identifier: package.identifier(),
+                    prefix: package.project_prefix(),
+                    paths_to_nodes,
+                });
+            self.package_done(package)?;
+            return Ok(Some(package.identifier()));
+        }
+
+        let Some(key) = self.grouping.key(package) else {
+            self.write_commit(
+                &format!("Add package {}", package.identifier()),
+                1,
+                paths_to_nodes,
+            )?;
+            return Ok(Some(package.identifier()));
+        };
+
+        let pending = self.pending.entry(key.clone()).or_default();
+        if pending.packages.is_empty() {
+            pending.first_package = package.identifier();
+        }
+        let commit_package = pending.first_package.clone();
+        pending.packages.push(format!(
+            "{} uploaded {} from {}",
+            package.identifier(),
+            package.upload_time.to_rfc3339(),
+            package.url
+        ));
+        pending.paths_to_nodes.extend(paths_to_nodes);
+
+        if let CommitGrouping::Count(count) = self.grouping {
+            if pending.packages.len() >= count {
+                let pending = self.pending.remove(&key).unwrap();
+                self.write_pending_commit(pending)?;
+            }
+        }
+        self.package_done(package)?;
+        Ok(Some(commit_package))
+    }
+
+    fn skip_package(&mut self, package: &RepositoryPackage) -> io::Result<()> {
+        self.package_done(package)
+    }
+
+    fn remove_paths(&mut self, name: &str, paths: Vec<String>) -> io::Result<()> {
+        if self.project_branches.is_some() {
+            return self.remove_project_paths(name, paths);
+        }
+        self.write_commit_header(&format!("Remove package {name}"))?;
+        for path in paths {
+            let path = path.trim_end_matches('/');
+            if path.is_empty() {
+                continue;
+            }
+            writeln!(self.output, "D {path}")?;
+        }
+        writeln!(self.output)?;
+        Ok(())
+    }
+
+    fn add_file(&mut self, data: Vec<u8>) -> io::Result<usize> {
         self.current_mark += 1;
         writeln!(self.output, "blob")?;
         writeln!(self.output, "mark :{}", self.current_mark)?;
@@ -106,3 +414,115 @@ impl<T: Write> GitFastImporter<T> {
         Ok(self.current_mark)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use itertools::Itertools;
+
+    fn package(name: &str, upload_time: &str) -> RepositoryPackage {
+        let url = format!("https://files.pythonhosted.org/packages/{name}-1.0.tar.gz");
+        RepositoryPackage {
+            project_name: name.to_string(),
+            project_version: "1.0".to_string(),
+            upload_time: upload_time.parse().unwrap(),
+            ..RepositoryPackage::fake_from_url(url.parse().unwrap())
+        }
+    }
+
+    // The first line of every commit message, which follows its `data` line. Blobs in the tests
+    // don't start with "Add ".
+    fn commit_subjects(output: &[u8]) -> Vec<String> {
+        String::from_utf8_lossy(output)
+            .lines()
+            .tuple_windows()
+            .filter(|(data, line)| data.starts_with("data ") && line.starts_with("Add "))
+            .map(|(_, line)| line.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn parse_grouping() {
+        assert_eq!("package".parse(), Ok(CommitGrouping::Package));
+        assert_eq!("project".parse(), Ok(CommitGrouping::Project));
+        assert_eq!("day".parse(), Ok(CommitGrouping::Day));
+        assert_eq!("count:50".parse(), Ok(CommitGrouping::Count(50)));
+        for invalid in ["count:0", "count:", "count:x", "week"] {
+            assert_eq!(
+                invalid.parse::<CommitGrouping>(),
+                Err(format!(
+                    "Unknown commit grouping {invalid}. Expected package, project, day or count:N"
+                ))
+            );
+        }
+    }
+
+    #[test]
+    fn grouping_keys() {
+        let package = package("foo", "2023-06-01T23:30:00Z");
+        assert_eq!(CommitGrouping::Package.key(&package), None);
+        assert_eq!(CommitGrouping::Count(2).key(&package), Some(String::new()));
+        assert_eq!(
+            CommitGrouping::Project.key(&package),
+            Some("foo".to_string())
+        );
+        assert_eq!(
+            CommitGrouping::Day.key(&package),
+            Some("2023-06-01".to_string())
+        );
+    }
+
+    #[test]
+    fn day_groups_are_written_once_complete() {
+        let first = package("foo", "2023-06-01T10:00:00Z");
+        let second = package("bar", "2023-06-01T11:00:00Z");
+        let other_day = package("baz", "2023-06-02T10:00:00Z");
+        let failed = package("qux", "2023-06-03T10:00:00Z");
+        let packages = [
+            first.clone(),
+            other_day.clone(),
+            second.clone(),
+            failed.clone(),
+        ];
+        let mut importer = GitFastImporter::new(
+            vec![],
+            &packages,
+            "code".to_string(),
+            false,
+            false,
+            CommitGrouping::Day,
+            None,
+        )
+        .into_inner()
+        .unwrap();
+
+        let node = importer.add_file(b"print()".to_vec()).unwrap();
+        let commit = importer
+            .flush_commit(&first, vec![(node, "a.py".to_string())])
+            .unwrap();
+        assert_eq!(commit, Some(first.identifier()));
+        assert!(commit_subjects(&importer.output).is_empty());
+
+        let commit = importer.flush_commit(&other_day, vec![]).unwrap();
+        assert_eq!(commit, Some(other_day.identifier()));
+        assert_eq!(
+            commit_subjects(&importer.output),
+            [format!("Add 1 packages from {}", other_day.identifier())]
+        );
+
+        let commit = importer.flush_commit(&second, vec![]).unwrap();
+        assert_eq!(commit, Some(first.identifier()));
+        importer.skip_package(&failed).unwrap();
+        assert_eq!(
+            commit_subjects(&importer.output),
+            [
+                format!("Add 1 packages from {}", other_day.identifier()),
+                format!("Add 2 packages from {}", first.identifier()),
+            ]
+        );
+
+        importer.finish().unwrap();
+        assert_eq!(commit_subjects(&importer.output).len(), 2);
+        assert!(String::from_utf8_lossy(&importer.output).ends_with("done\n"));
+    }
+}
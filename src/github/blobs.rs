@@ -0,0 +1,28 @@
+use crate::github::{get_client, GithubError};
+use crate::http::HttpClient;
+use std::io::Read;
+
+/// Streams the raw contents of a blob from a repository in the organisation. Works without a
+/// token for public repositories, at a much lower rate limit.
+pub fn get_blob(
+    client: Option<HttpClient>,
+    token: Option<&str>,
+    repository: &str,
+    sha: &str,
+) -> Result<impl Read + Send, GithubError> {
+    let client = match client {
+        Some(client) => client,
+        None => get_client()?,
+    };
+    let mut request = client
+        .get(&format!(
+            "https://api.github.com/repos/pypi-data/{repository}/git/blobs/{sha}"
+        ))
+        .set("X-GitHub-Api-Version", "2022-11-28")
+        .set("Accept", "application/vnd.github.raw+json");
+    if let Some(token) = token {
+        request = request.set("Authorization", &format!("bearer {token}"));
+    }
+    let response = request.call().map_err(Box::new)?;
+    Ok(response.into_reader())
+}
@@ -7,7 +7,8 @@ use serde::{Deserialize, Serialize};
 use std::thread::sleep;
 use std::time::Duration;
 
-use ureq::{Agent, Error};
+use crate::http::HttpClient;
+use ureq::Error;
 
 pub const REPO_CODE_PREFIX: &str = "pypi-mirror-";
 
@@ -25,7 +26,7 @@ pub struct TemplateData {
     owner_id: String,
 }
 
-pub fn get_template_data(client: &Agent, token: &str) -> Result<TemplateData, GithubError> {
+pub fn get_template_data(client: &HttpClient, token: &str) -> Result<TemplateData, GithubError> {
     let variables = get_template_data::Variables {};
     let request_body = GetTemplateData::build_query(variables);
     let response = client
@@ -53,7 +54,7 @@ pub fn get_template_data(client: &Agent, token: &str) -> Result<TemplateData, Gi
 pub struct CreateRepo;
 
 pub fn create_repository(
-    client: &Agent,
+    client: &HttpClient,
     token: &str,
     template_data: &TemplateData,
     index: usize,
@@ -91,7 +92,7 @@ pub struct CreateDeployKey {
 }
 
 pub fn create_deploy_key(
-    client: &Agent,
+    client: &HttpClient,
     token: &str,
     name_with_owner: &str,
 ) -> anyhow::Result<String> {
@@ -144,7 +145,7 @@ pub struct RepoPublicKey {
 }
 
 fn get_repo_public_key(
-    client: &Agent,
+    client: &HttpClient,
     token: &str,
     name_with_owner: &str,
 ) -> Result<(Vec<u8>, String), GithubError> {
@@ -172,7 +173,7 @@ pub struct CreateSecret {
 }
 
 fn create_actions_secret(
-    client: &Agent,
+    client: &HttpClient,
     token: &str,
     name_with_owner: &str,
     encrypted_value: String,
@@ -209,7 +210,7 @@ pub struct UpdateDescription {
 }
 
 pub fn update_description(
-    client: &Agent,
+    client: &HttpClient,
     token: &str,
     name_with_owner: &str,
     description: String,
@@ -1,4 +1,5 @@
 use crate::github::{get_client, GithubError};
+use crate::http::HttpClient;
 use crate::repository::index::RepositoryIndex;
 use anyhow::{bail, Context};
 use base64::engine::general_purpose;
@@ -7,13 +8,14 @@ use serde::{Deserialize, Serialize};
 use std::thread::sleep;
 use std::time::Duration;
 
-use ureq::Agent;
-
 pub fn get_repository_index(
     name: &str,
-    client: Option<Agent>,
+    client: Option<HttpClient>,
 ) -> Result<RepositoryIndex, GithubError> {
-    let client = client.unwrap_or_else(get_client);
+    let client = match client {
+        Some(client) => client,
+        None => get_client()?,
+    };
 
     let response = client
         .get(&format!(
@@ -39,7 +41,7 @@ pub struct GetFile {
 }
 
 pub fn upload_index_file(
-    client: &Agent,
+    client: &HttpClient,
     token: &str,
     name_with_owner: &str,
     // file: UploadFile,
@@ -1,9 +1,12 @@
+use crate::http::HttpClient;
 use std::io;
 use thiserror::Error;
 
+pub mod blobs;
 pub mod create;
 pub mod index;
 pub mod projects;
+pub mod releases;
 pub mod status;
 pub mod trigger_ci;
 pub mod workflows;
@@ -21,10 +24,11 @@ pub enum GithubError {
 
     #[error("Serde error: {0}")]
     SerdeError(#[from] anyhow::Error),
+
+    #[error("HTTP client error: {0:#}")]
+    ClientError(anyhow::Error),
 }
 
-pub fn get_client() -> ureq::Agent {
-    ureq::AgentBuilder::new()
-        .user_agent("pypi-data/toolchain")
-        .build()
+pub fn get_client() -> Result<HttpClient, GithubError> {
+    crate::http::client().map_err(GithubError::ClientError)
 }
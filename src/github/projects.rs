@@ -24,7 +24,7 @@ impl DataRepo {
 }
 
 pub fn get_all_pypi_data_repos(token: &str) -> Result<Vec<DataRepo>, GithubError> {
-    let client = get_client();
+    let client = get_client()?;
     let mut cursor = None;
     let mut repo_names = vec![];
     loop {
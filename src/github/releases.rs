@@ -0,0 +1,45 @@
+use crate::github::{get_client, GithubError};
+use crate::http::HttpClient;
+use serde::Deserialize;
+
+#[derive(Deserialize, Debug)]
+pub struct ReleaseAsset {
+    pub name: String,
+    pub size: u64,
+    pub browser_download_url: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct Release {
+    assets: Vec<ReleaseAsset>,
+}
+
+/// Lists the assets of a release of a repository in the organisation, or `None` if the release
+/// doesn't exist.
+pub fn get_release_assets(
+    client: Option<HttpClient>,
+    token: &str,
+    repository: &str,
+    tag: &str,
+) -> Result<Option<Vec<ReleaseAsset>>, GithubError> {
+    let client = match client {
+        Some(client) => client,
+        None => get_client()?,
+    };
+    let response = client
+        .get(&format!(
+            "https://api.github.com/repos/pypi-data/{repository}/releases/tags/{tag}"
+        ))
+        .set("Authorization", &format!("bearer {token}"))
+        .set("X-GitHub-Api-Version", "2022-11-28")
+        .set("Accept", "application/vnd.github+json")
+        .call();
+    match response {
+        Ok(response) => {
+            let release: Release = response.into_json()?;
+            Ok(Some(release.assets))
+        }
+        Err(ureq::Error::Status(404, _)) => Ok(None),
+        Err(e) => Err(Box::new(e).into()),
+    }
+}
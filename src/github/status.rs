@@ -24,7 +24,7 @@ pub fn get_status(
     limit: Option<usize>,
 ) -> Result<Vec<RepoStatus>, GithubError> {
     let all_repos = github::projects::get_all_pypi_data_repos(github_token)?;
-    let client = github::get_client();
+    let client = github::get_client()?;
     let limit = limit.unwrap_or(all_repos.len());
     let indexes: Result<Vec<RepoStatus>, GithubError> = all_repos
         .into_par_iter()
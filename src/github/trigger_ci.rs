@@ -18,7 +18,7 @@ pub fn trigger_ci_workflow(
     name_with_owner: &str,
     limit: usize,
 ) -> Result<(), GithubError> {
-    let client = get_client();
+    let client = get_client()?;
     client
         .post(&format!(
             "https://api.github.com/repos/{name_with_owner}/actions/workflows/trigger.yml/dispatches"
@@ -1,8 +1,8 @@
 use crate::github::{get_client, GithubError};
+use crate::http::HttpClient;
 
 use anyhow::Context;
 use serde::{Deserialize, Serialize};
-use ureq::Agent;
 
 #[derive(Deserialize, Serialize, Debug)]
 #[serde(rename_all = "snake_case")]
@@ -39,10 +39,13 @@ pub struct WorkflowRuns {
 pub fn get_workflow_runs(
     token: &str,
     name: &str,
-    client: Option<Agent>,
+    client: Option<HttpClient>,
     limit: usize,
 ) -> Result<WorkflowRuns, GithubError> {
-    let client = client.unwrap_or_else(get_client);
+    let client = match client {
+        Some(client) => client,
+        None => get_client()?,
+    };
 
     let response = client
         .get(&format!("https://api.github.com/repos/pypi-data/{name}/actions/workflows/trigger.yml/runs?branch=main&exclude_pull_requests=true&per_page={limit}"))
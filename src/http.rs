@@ -0,0 +1,200 @@
+use anyhow::Context;
+use clap::Args;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::CertificateDer;
+use std::path::PathBuf;
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+use ureq::{Agent, AgentBuilder, Proxy, Request};
+use url::Url;
+
+static OPTIONS: OnceLock<HttpOptions> = OnceLock::new();
+static CLIENT: OnceLock<HttpClient> = OnceLock::new();
+
+#[derive(Args, Clone, Debug)]
+pub struct HttpOptions {
+    /// Proxy to send all requests through. Defaults to the standard proxy environment variables.
+    #[clap(long, global = true)]
+    pub proxy: Option<String>,
+
+    /// PEM file with additional root certificates to trust.
+    #[clap(long, global = true, env = "PYPI_DATA_CA_BUNDLE")]
+    pub ca_bundle: Option<PathBuf>,
+
+    #[clap(long, global = true, default_value = "10")]
+    pub connect_timeout_seconds: u64,
+
+    #[clap(long, global = true, default_value = "60")]
+    pub read_timeout_seconds: u64,
+
+    /// Total timeout for requests to a host, as `host=seconds`.
+    #[clap(
+        long = "host-timeout",
+        global = true,
+        value_parser = parse_pair::<u64>,
+        default_value = "files.pythonhosted.org=30"
+    )]
+    pub host_timeouts: Vec<(String, u64)>,
+
+    /// Rewrite URLs starting with a prefix, as `prefix=replacement`. Useful for mirrors.
+    #[clap(long = "url-override", global = true, value_parser = parse_pair::<String>)]
+    pub url_overrides: Vec<(String, String)>,
+}
+
+fn parse_pair<T: std::str::FromStr>(value: &str) -> Result<(String, T), String> {
+    let (key, value) = value
+        .split_once('=')
+        .ok_or_else(|| format!("Expected key=value, got {value}"))?;
+    let value = value
+        .parse()
+        .map_err(|_| format!("Invalid value for {key}: {value}"))?;
+    Ok((key.to_string(), value))
+}
+
+impl HttpOptions {
+    /// Arguments that reproduce these options in a child process.
+    pub fn to_args(&self) -> Vec<String> {
+        let mut args = vec![
+            format!("--connect-timeout-seconds={}", self.connect_timeout_seconds),
+            format!("--read-timeout-seconds={}", self.read_timeout_seconds),
+        ];
+        if let Some(proxy) = &self.proxy {
+            args.push(format!("--proxy={proxy}"));
+        }
+        if let Some(ca_bundle) = &self.ca_bundle {
+            args.push(format!("--ca-bundle={}", ca_bundle.display()));
+        }
+        for (host, timeout) in &self.host_timeouts {
+            args.push(format!("--host-timeout={host}={timeout}"));
+        }
+        for (prefix, replacement) in &self.url_overrides {
+            args.push(format!("--url-override={prefix}={replacement}"));
+        }
+        args
+    }
+}
+
+impl Default for HttpOptions {
+    fn default() -> Self {
+        HttpOptions {
+            proxy: None,
+            ca_bundle: None,
+            connect_timeout_seconds: 10,
+            read_timeout_seconds: 60,
+            host_timeouts: vec![("files.pythonhosted.org".to_string(), 30)],
+            url_overrides: vec![],
+        }
+    }
+}
+
+/// A pooled HTTP agent shared by everything that talks to PyPI or GitHub.
+#[derive(Clone, Debug)]
+pub struct HttpClient {
+    agent: Agent,
+    options: Arc<HttpOptions>,
+}
+
+impl HttpClient {
+    pub fn new(options: HttpOptions) -> anyhow::Result<Self> {
+        let mut builder = AgentBuilder::new()
+            .user_agent("pypi-data/toolchain")
+            .max_idle_connections_per_host(32)
+            .timeout_connect(Duration::from_secs(options.connect_timeout_seconds))
+            .timeout_read(Duration::from_secs(options.read_timeout_seconds))
+            .try_proxy_from_env(true);
+        if let Some(proxy) = &options.proxy {
+            builder = builder.proxy(Proxy::new(proxy)?);
+        }
+        if let Some(ca_bundle) = &options.ca_bundle {
+            let mut roots = rustls::RootCertStore {
+                roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+            };
+            for cert in CertificateDer::pem_file_iter(ca_bundle)? {
+                roots.add(cert?)?;
+            }
+            let provider = Arc::new(rustls::crypto::ring::default_provider());
+            let tls_config = rustls::ClientConfig::builder_with_provider(provider)
+                .with_protocol_versions(rustls::ALL_VERSIONS)?
+                .with_root_certificates(roots)
+                .with_no_client_auth();
+            builder = builder.tls_config(Arc::new(tls_config));
+        }
+        Ok(HttpClient {
+            agent: builder.build(),
+            options: Arc::new(options),
+        })
+    }
+
+    pub fn options(&self) -> &HttpOptions {
+        &self.options
+    }
+
+    pub fn request(&self, method: &str, url: &str) -> Request {
+        let url = self
+            .options
+            .url_overrides
+            .iter()
+            .find_map(|(prefix, replacement)| {
+                url.strip_prefix(prefix.as_str())
+                    .map(|rest| format!("{replacement}{rest}"))
+            })
+            .unwrap_or_else(|| url.to_string());
+        let timeout = Url::parse(&url).ok().and_then(|parsed| {
+            let host = parsed.host_str()?;
+            self.options
+                .host_timeouts
+                .iter()
+                .find(|(h, _)| h == host)
+                .map(|(_, timeout)| Duration::from_secs(*timeout))
+        });
+        let request = self.agent.request(method, &url);
+        match timeout {
+            Some(timeout) => request.timeout(timeout),
+            None => request,
+        }
+    }
+
+    pub fn request_url(&self, method: &str, url: &Url) -> Request {
+        self.request(method, url.as_str())
+    }
+
+    pub fn get(&self, url: &str) -> Request {
+        self.request("GET", url)
+    }
+
+    pub fn post(&self, url: &str) -> Request {
+        self.request("POST", url)
+    }
+
+    pub fn put(&self, url: &str) -> Request {
+        self.request("PUT", url)
+    }
+
+    pub fn patch(&self, url: &str) -> Request {
+        self.request("PATCH", url)
+    }
+}
+
+/// Configures the shared client. Must be called before the first call to `client()`. The client
+/// is only built when it is first used, so that a bad proxy or CA bundle doesn't break commands
+/// that never touch the network.
+pub fn init(options: HttpOptions) -> anyhow::Result<()> {
+    OPTIONS
+        .set(options)
+        .map_err(|_| anyhow::anyhow!("HTTP client already initialised"))
+}
+
+pub fn client() -> anyhow::Result<HttpClient> {
+    if let Some(client) = CLIENT.get() {
+        return Ok(client.clone());
+    }
+    let mut options = OPTIONS.get().cloned().unwrap_or_default();
+    // The options are passed on to child processes, which may run in another directory.
+    if let Some(ca_bundle) = &options.ca_bundle {
+        let ca_bundle = std::fs::canonicalize(ca_bundle)
+            .with_context(|| format!("Reading CA bundle {}", ca_bundle.display()))?;
+        options.ca_bundle = Some(ca_bundle);
+    }
+    let client = HttpClient::new(options)?;
+    Ok(CLIENT.get_or_init(|| client).clone())
+}
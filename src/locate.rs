@@ -0,0 +1,157 @@
+use std::collections::HashMap;
+use std::path::Path;
+
+use anyhow::bail;
+use git2::Oid;
+use indicatif::ParallelProgressIterator;
+use itertools::Itertools;
+use polars::prelude::*;
+use rayon::prelude::*;
+
+use crate::github;
+use crate::github::create::REPO_CODE_PREFIX;
+use crate::repository::filename::PackageType;
+use crate::repository::index::RepositoryIndex;
+use crate::repository::package::{normalize_project_name, RepositoryPackage};
+
+/// What `locate` looks for, guessed from the shape of the argument.
+#[derive(Debug)]
+pub enum LocateQuery {
+    Hash(Vec<u8>),
+    Release(String),
+    Project(String),
+}
+
+impl LocateQuery {
+    pub fn parse(query: &str) -> Self {
+        if query.len() == 40 {
+            if let Ok(oid) = Oid::from_str(query) {
+                return LocateQuery::Hash(oid.as_bytes().to_vec());
+            }
+        }
+        match PackageType::from_filename(query) {
+            PackageType::Other => LocateQuery::Project(normalize_project_name(query)),
+            _ => LocateQuery::Release(query.to_string()),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Location<'a> {
+    pub repository: String,
+    pub package: &'a RepositoryPackage,
+    /// Paths of matching files, when a dataset was given.
+    pub paths: Vec<String>,
+}
+
+impl Location<'_> {
+    pub fn status(&self) -> String {
+        match (self.package.status(), &self.package.truncated) {
+            ("truncated", Some(reason)) => format!("truncated: {reason}"),
+            (status, _) => status.to_string(),
+        }
+    }
+
+    /// Where the release is on the `code` branch of its repository.
+    pub fn url(&self) -> String {
+        format!(
+            "https://github.com/pypi-data/{}/tree/code/{}",
+            self.repository,
+            self.package.file_prefix()
+        )
+    }
+}
+
+/// Loads the index of every repository, from `index_dir` if it has been cached there before.
+/// Otherwise they are downloaded from GitHub, and cached in `index_dir` if it was given.
+pub fn load_indexes(
+    index_dir: Option<&Path>,
+    github_token: Option<&str>,
+    refresh: bool,
+) -> anyhow::Result<Vec<RepositoryIndex>> {
+    if let Some(index_dir) = index_dir.filter(|d| d.exists() && !refresh) {
+        return std::fs::read_dir(index_dir)?
+            .map(|entry| entry.map(|e| e.path()))
+            .filter_ok(|path| {
+                path.extension()
+                    .is_some_and(|ext| ext == "json" || ext == "gz")
+            })
+            .map(|path| Ok(RepositoryIndex::from_path(&path?)?))
+            .collect();
+    }
+    let Some(github_token) = github_token else {
+        bail!("A GitHub token is needed to download the repository indexes");
+    };
+    let repos = github::projects::get_all_pypi_data_repos(github_token)?;
+    let client = github::get_client()?;
+    let indexes = repos
+        .into_par_iter()
+        .progress()
+        .map(|repo| github::index::get_repository_index(&repo.name, Some(client.clone())))
+        .collect::<Result<Vec<_>, _>>()?;
+    if let Some(index_dir) = index_dir {
+        std::fs::create_dir_all(index_dir)?;
+        for index in &indexes {
+            index.to_file(&index_dir.join(index.file_name()))?;
+        }
+    }
+    Ok(indexes)
+}
+
+/// Paths of files in the dataset matching the filter, by release.
+fn dataset_paths(dataset: LazyFrame, filter: Expr) -> anyhow::Result<HashMap<String, Vec<String>>> {
+    let df = dataset
+        .filter(filter)
+        .select([col("project_release"), col("path")])
+        .collect()?;
+    let releases = df.column("project_release")?.str()?;
+    let paths = df.column("path")?.str()?;
+    Ok(releases
+        .into_iter()
+        .zip(paths)
+        .filter_map(|(release, path)| Some((release?.to_string(), path?.to_string())))
+        .into_group_map())
+}
+
+/// Finds the repositories that hold a project, a release or a file. Files can only be found by
+/// hash in a dataset, and the paths of a release are only known with a dataset.
+pub fn locate<'a>(
+    query: &LocateQuery,
+    indexes: &'a [RepositoryIndex],
+    dataset: Option<LazyFrame>,
+) -> anyhow::Result<Vec<Location<'a>>> {
+    let paths = match (query, dataset) {
+        (LocateQuery::Hash(_), None) => bail!("Finding a file by hash needs a dataset"),
+        (LocateQuery::Hash(hash), Some(dataset)) => {
+            dataset_paths(dataset, col("hash").eq(lit(hash.clone())))?
+        }
+        (LocateQuery::Release(release), Some(dataset)) => {
+            dataset_paths(dataset, col("project_release").eq(lit(release.as_str())))?
+        }
+        _ => HashMap::new(),
+    };
+    let locations = indexes
+        .iter()
+        .sorted_by_key(|index| index.index())
+        .flat_map(|index| {
+            index
+                .packages()
+                .iter()
+                .map(move |package| (index.index(), package))
+        })
+        .filter(|(_, package)| match query {
+            LocateQuery::Hash(_) => paths.contains_key(package.package_filename()),
+            LocateQuery::Release(release) => package.package_filename() == release,
+            LocateQuery::Project(name) => &package.normalized_project_name() == name,
+        })
+        .map(|(index, package)| Location {
+            repository: format!("{REPO_CODE_PREFIX}{index}"),
+            package,
+            paths: paths
+                .get(package.package_filename())
+                .cloned()
+                .unwrap_or_default(),
+        })
+        .collect();
+    Ok(locations)
+}
@@ -5,7 +5,7 @@ use cli_table::{Cell, Style, Table};
 use flate2::bufread::ZlibDecoder;
 use flate2::write::ZlibEncoder;
 use flate2::Compression;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::fs::File;
 use std::io;
 use std::io::{BufReader, BufWriter, Write};
@@ -21,22 +21,39 @@ use rand::seq::SliceRandom;
 use rayon::prelude::*;
 use rusqlite::Connection;
 use serde::Serialize;
+use tracing::{event, Level};
 use url::Url;
 
-use crate::extract::download_packages;
-use crate::git::GitFastImporter;
+use crate::extract::{download_packages, ExtractOptions, PathLayout};
+use crate::git::{CommitGrouping, GitFastImporter};
 use crate::github::GithubError;
 use crate::repository::index::RepositoryIndex;
-use crate::repository::package::RepositoryPackage;
+use crate::repository::package::{normalize_project_name, RepositoryPackage};
+use crate::sink::directory::DirectorySink;
+use crate::sink::tar::TarSink;
+use crate::sink::OutputSink;
+use crate::worker::WorkerLimits;
 
+mod analysis;
 mod archive;
+mod catalog;
 mod data;
+mod dataset;
+mod download;
+mod export;
 mod extract;
 mod git;
 mod github;
+mod http;
+mod locate;
+mod metadata;
+mod mirror;
+mod query;
 mod readme;
 mod repository;
+mod sink;
 mod site;
+mod worker;
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
@@ -46,6 +63,9 @@ struct Cli {
 
     #[clap(long)]
     tracing_file: Option<PathBuf>,
+
+    #[command(flatten)]
+    http: http::HttpOptions,
 }
 
 #[derive(Subcommand)]
@@ -65,6 +85,66 @@ enum Commands {
 
         #[clap(short, long, default_value = "false")]
         skip_contents: bool,
+
+        #[clap(long, conflicts_with = "output_tar")]
+        output_dir: Option<PathBuf>,
+
+        #[clap(long)]
+        output_tar: Option<PathBuf>,
+
+        /// How packages are grouped into commits: package (the default), project, day or count:N.
+        #[clap(long)]
+        group_commits: Option<CommitGrouping>,
+
+        /// Commit every release to a `projects/<name>` branch of its project instead of `code`.
+        /// This creates one ref per project, hundreds of thousands for a full repository, which
+        /// makes fetches that don't restrict their refspec slow. Off by default for that reason,
+        /// and meant for local mirrors: the workflows only push `code` and `main`.
+        #[clap(long, conflicts_with_all = ["output_dir", "output_tar", "group_commits"])]
+        project_history: bool,
+
+        #[clap(long)]
+        normalize_paths: bool,
+
+        #[clap(long)]
+        tolerant: bool,
+
+        /// Leave files containing secrets out of the output.
+        #[clap(long)]
+        exclude_secrets: bool,
+
+        #[clap(long)]
+        isolated: bool,
+
+        #[clap(long, requires = "isolated")]
+        worker_memory_limit_mb: Option<u64>,
+
+        #[clap(long, requires = "isolated")]
+        worker_cpu_limit_seconds: Option<u64>,
+
+        /// TOML file with the rules used to flag suspicious code, replacing the built-in ones.
+        #[clap(long)]
+        rules: Option<PathBuf>,
+    },
+    #[command(hide = true)]
+    ExtractWorker {
+        #[clap(long)]
+        rules: Option<PathBuf>,
+    },
+    RemovePackages {
+        directory: PathBuf,
+
+        identifiers: Vec<String>,
+
+        #[clap(short, long)]
+        project: Vec<String>,
+
+        #[clap(long)]
+        output_dir: Option<PathBuf>,
+
+        /// The packages were extracted with `--project-history`.
+        #[clap(long, conflicts_with = "output_dir")]
+        project_history: bool,
     },
     GenerateReadme {
         repository_dir: PathBuf,
@@ -74,7 +154,17 @@ enum Commands {
 
         output_file: PathBuf,
 
-        input_dir: PathBuf,
+        /// New batch files, oldest first.
+        #[clap(required_unless_present = "existing")]
+        batch_files: Vec<PathBuf>,
+
+        /// Dataset to add the batch files to. Batch files it already contains are skipped.
+        #[clap(long)]
+        existing: Option<PathBuf>,
+    },
+    /// Print the batch files a merged dataset contains, one per line.
+    MergedFiles {
+        dataset: PathBuf,
     },
 
     // Creation/bootstrap commands
@@ -139,9 +229,136 @@ enum Commands {
         #[clap(short, long)]
         reload_from: Option<PathBuf>,
     },
+    BuildDataset {
+        input_dir: PathBuf,
+
+        output_dir: PathBuf,
+
+        /// upload-month or repository
+        #[clap(long, default_value = "upload-month")]
+        partition_by: dataset::Partitioning,
+    },
+    /// Query the datasets downloaded by get-all-indexes or written by build-dataset
+    Query {
+        dataset: PathBuf,
+
+        /// SQL to run against the `files` table, e.g. "SELECT path, size FROM files WHERE size > 1000000"
+        #[clap(long)]
+        sql: Option<String>,
+
+        #[clap(flatten)]
+        filters: query::Filters,
+
+        #[clap(short, long)]
+        limit: Option<usize>,
+
+        /// table, csv, json or parquet
+        #[clap(long, default_value = "table")]
+        format: query::OutputFormat,
+
+        #[clap(short, long)]
+        output: Option<PathBuf>,
+    },
+    /// Find the repositories that hold a project, a release filename or a file hash
+    Locate {
+        query: String,
+
+        /// Cache of the repository indexes. Downloaded from GitHub when it doesn't exist.
+        #[clap(long)]
+        index_dir: Option<PathBuf>,
+
+        /// Download the repository indexes again, even if they are cached
+        #[clap(long)]
+        refresh: bool,
+
+        /// Dataset to find the paths of files in, from get-all-indexes or build-dataset
+        #[clap(long)]
+        dataset: Option<PathBuf>,
+
+        #[clap(long, env)]
+        github_token: Option<String>,
+    },
+    /// Write the contents of a file in the repositories to stdout
+    Cat {
+        /// Dataset to find the file in, from get-all-indexes or build-dataset
+        dataset: PathBuf,
+
+        #[clap(flatten)]
+        selector: export::FileSelector,
+
+        /// Directory with local clones of the repositories, e.g. from sync, tried before the
+        /// GitHub API
+        #[clap(long, env = "PYPI_DATA_MIRROR")]
+        repositories_dir: Option<PathBuf>,
+
+        #[clap(long, env)]
+        github_token: Option<String>,
+    },
+    /// Write the files in the repositories matching a selector, e.g. a whole release, to a directory
+    Export {
+        /// Dataset to find the files in, from get-all-indexes or build-dataset
+        dataset: PathBuf,
+
+        output_dir: PathBuf,
+
+        #[clap(flatten)]
+        selector: export::FileSelector,
+
+        /// Directory with local clones of the repositories, e.g. from sync, tried before the
+        /// GitHub API
+        #[clap(long, env = "PYPI_DATA_MIRROR")]
+        repositories_dir: Option<PathBuf>,
+
+        #[clap(long, env)]
+        github_token: Option<String>,
+    },
+    /// Clone new repositories and fetch the code branch of existing ones into a local mirror
+    Sync {
+        mirror_dir: PathBuf,
+
+        /// Objects new clones skip until they are needed: full, blobless or treeless
+        #[clap(long, default_value = "full")]
+        filter: mirror::MirrorFilter,
+
+        /// Number of repositories to fetch at once
+        #[clap(short, long, default_value = "4")]
+        jobs: usize,
+
+        #[clap(long, env)]
+        github_token: String,
+    },
+    /// Write a JSON catalog describing every repository and the dataset it publishes
+    Catalog {
+        output_file: PathBuf,
+
+        /// Directory written by get-all-indexes, to include the row counts, hashes and schema
+        /// versions of the datasets
+        #[clap(long)]
+        datasets_dir: Option<PathBuf>,
+
+        /// Compress the catalog with zlib, like the static site
+        #[clap(long)]
+        compress: bool,
+
+        #[clap(long, env)]
+        github_token: String,
+    },
+    /// Download the published files of every repository, skipping those that haven't changed
     GetAllIndexes {
         output_dir: PathBuf,
 
+        /// Files to download: dataset, index or batches
+        #[clap(long, value_delimiter = ',', default_value = "dataset")]
+        assets: Vec<download::Asset>,
+
+        /// Number of repositories to download at once
+        #[clap(short, long, default_value = "8")]
+        jobs: usize,
+
+        /// Attempts after a transient error, before a download fails
+        #[clap(long, default_value = "5")]
+        retries: usize,
+
         #[clap(long, env)]
         github_token: String,
     },
@@ -159,11 +376,30 @@ enum Commands {
 
         #[clap(short, long, default_value = "false")]
         skip_contents: bool,
+
+        #[clap(long, conflicts_with = "output_tar")]
+        output_dir: Option<PathBuf>,
+
+        #[clap(long)]
+        output_tar: Option<PathBuf>,
     },
 }
 
+/// Projects with a `projects/<name>` branch from `--project-history`.
+fn project_branches(git_repo: &Repository) -> Result<HashSet<String>, git2::Error> {
+    Ok(git_repo
+        .branches(Some(BranchType::Local))?
+        .flatten()
+        .filter_map(|(branch, _)| {
+            let name = branch.name().ok().flatten()?;
+            name.strip_prefix("projects/").map(|n| n.to_string())
+        })
+        .collect())
+}
+
 fn main() -> anyhow::Result<()> {
     let cli = Cli::parse();
+    http::init(cli.http.clone())?;
     let _guard = if let Some(tracing_file) = cli.tracing_file {
         let log_file = File::create(tracing_file)?;
         let (non_blocking, _guard) = tracing_appender::non_blocking(log_file);
@@ -181,10 +417,33 @@ fn main() -> anyhow::Result<()> {
         Commands::MergeParquet {
             index_path,
             output_file,
-            input_dir,
+            batch_files,
+            existing,
         } => {
             let repo_index = RepositoryIndex::from_path(&index_path)?;
-            data::merge_parquet_files(&input_dir, &output_file, repo_index.index())?;
+            let removed_releases = repo_index
+                .removed_packages()
+                .map(|p| p.package_filename().to_string())
+                .collect();
+            let summary = data::merge_parquet_files(
+                existing.as_deref(),
+                &batch_files,
+                &output_file,
+                repo_index.index(),
+                removed_releases,
+            )?;
+            for file in summary.skipped_files {
+                eprintln!("Skipped {file}: already merged");
+            }
+            println!(
+                "Added {} rows, replaced {} rows, dropped {} rows, discarded {} batch rows",
+                summary.added, summary.replaced, summary.dropped, summary.discarded
+            );
+        }
+        Commands::MergedFiles { dataset } => {
+            for file in data::read_merged_files(&dataset)? {
+                println!("{file}");
+            }
         }
 
         Commands::Extract {
@@ -193,12 +452,35 @@ fn main() -> anyhow::Result<()> {
             index_file_name,
             filter_name,
             skip_contents,
+            output_dir,
+            output_tar,
+            group_commits,
+            project_history,
+            normalize_paths,
+            tolerant,
+            exclude_secrets,
+            isolated,
+            worker_memory_limit_mb,
+            worker_cpu_limit_seconds,
+            rules,
         } => {
-            let git_repo = Repository::open(&directory)?;
-            let has_code_branch = git_repo
-                .find_branch("code", BranchType::Local)
-                .map(|_| true)
-                .unwrap_or_default();
+            if let Some(rules) = rules {
+                analysis::rules::init(&rules)?;
+            }
+            let options = ExtractOptions {
+                layout: if project_history {
+                    PathLayout::Projects
+                } else {
+                    PathLayout::Packages
+                },
+                normalize_paths,
+                tolerant,
+                exclude_secrets,
+                isolation: isolated.then_some(WorkerLimits {
+                    memory_mb: worker_memory_limit_mb,
+                    cpu_seconds: worker_cpu_limit_seconds,
+                }),
+            };
             let repo_index_file = directory.join("index.json");
             let repo_file_index_path = directory.join(index_file_name);
             let mut repo_index = RepositoryIndex::from_path(&repo_index_file)?;
@@ -211,19 +493,133 @@ fn main() -> anyhow::Result<()> {
                     unprocessed_packages.drain(limit..);
                 }
             }
-            let output = GitFastImporter::new(
-                std::io::BufWriter::new(io::stdout()),
-                unprocessed_packages.len(),
-                "code".to_string(),
-                has_code_branch,
-                skip_contents,
-            );
-            let processed_packages =
-                download_packages(unprocessed_packages, repo_file_index_path, output)?;
+            let processed_packages = match (output_dir, output_tar) {
+                (Some(output_dir), _) => download_packages(
+                    unprocessed_packages,
+                    repo_file_index_path,
+                    DirectorySink::new(output_dir, skip_contents)?,
+                    options,
+                )?,
+                (None, Some(output_tar)) => download_packages(
+                    unprocessed_packages,
+                    repo_file_index_path,
+                    TarSink::new(&output_tar, skip_contents)?,
+                    options,
+                )?,
+                (None, None) => {
+                    let git_repo = Repository::open(&directory)?;
+                    let has_code_branch = git_repo
+                        .find_branch("code", BranchType::Local)
+                        .map(|_| true)
+                        .unwrap_or_default();
+                    let project_branches = if project_history {
+                        Some(project_branches(&git_repo)?)
+                    } else {
+                        None
+                    };
+                    let output = GitFastImporter::new(
+                        std::io::BufWriter::new(io::stdout()),
+                        &unprocessed_packages,
+                        "code".to_string(),
+                        has_code_branch,
+                        skip_contents,
+                        group_commits.unwrap_or(CommitGrouping::Package),
+                        project_branches,
+                    );
+                    download_packages(unprocessed_packages, repo_file_index_path, output, options)?
+                }
+            };
 
             repo_index.mark_packages_as_processed(processed_packages);
             repo_index.to_file(&repo_index_file)?;
         }
+        Commands::ExtractWorker { rules } => {
+            if let Some(rules) = rules {
+                analysis::rules::init(&rules)?;
+            }
+            worker::run_worker()?
+        }
+        Commands::RemovePackages {
+            directory,
+            identifiers,
+            project,
+            output_dir,
+            project_history,
+        } => {
+            let repo_index_file = directory.join("index.json");
+            let mut repo_index = RepositoryIndex::from_path(&repo_index_file)?;
+            let projects: Vec<_> = project.iter().map(|p| normalize_project_name(p)).collect();
+            let removed_packages = repo_index.mark_packages_as_removed(|p| {
+                identifiers.contains(&p.identifier())
+                    || projects.contains(&p.normalized_project_name())
+            });
+            event!(
+                Level::INFO,
+                "Marked {} packages as removed",
+                removed_packages.len()
+            );
+
+            let options = ExtractOptions {
+                layout: if project_history {
+                    PathLayout::Projects
+                } else {
+                    PathLayout::Packages
+                },
+                ..Default::default()
+            };
+            // Only packages that have been extracted have anything to delete. In the project
+            // layout a newer release of the same kind has already replaced the removed one.
+            let replaced = |package: &RepositoryPackage| {
+                project_history
+                    && repo_index.packages().iter().any(|p| {
+                        p.processed
+                            && !p.removed
+                            && p.upload_time > package.upload_time
+                            && options.output_prefix(p) == options.output_prefix(package)
+                    })
+            };
+            let (names, paths): (Vec<_>, Vec<_>) = removed_packages
+                .iter()
+                .filter(|p| p.processed && !replaced(p))
+                .map(|p| (p.identifier(), options.output_prefix(p)))
+                .unique_by(|(_, path)| path.clone())
+                .unzip();
+            if !paths.is_empty() {
+                let name = names.join(", ");
+                match output_dir {
+                    Some(output_dir) => {
+                        let mut output =
+                            DirectorySink::new(output_dir, false)?.into_inner().unwrap();
+                        output.remove_paths(&name, paths)?;
+                        output.finish()?;
+                    }
+                    None => {
+                        let git_repo = Repository::open(&directory)?;
+                        let has_code_branch = git_repo
+                            .find_branch("code", BranchType::Local)
+                            .map(|_| true)
+                            .unwrap_or_default();
+                        let mut output = GitFastImporter::new(
+                            std::io::BufWriter::new(io::stdout()),
+                            &[],
+                            "code".to_string(),
+                            has_code_branch,
+                            false,
+                            CommitGrouping::Package,
+                            project_history
+                                .then(|| project_branches(&git_repo))
+                                .transpose()?,
+                        )
+                        .into_inner()
+                        .unwrap();
+                        output.remove_paths(&name, paths)?;
+                        output.finish()?;
+                    }
+                }
+            }
+
+            repo_index.to_file(&repo_index_file)?;
+        }
         Commands::GenerateReadme { repository_dir } => {
             let index = RepositoryIndex::from_path(&repository_dir.join("index.json"))?;
             println!("{}", readme::generate_readme(index)?)
@@ -239,7 +635,7 @@ fn main() -> anyhow::Result<()> {
         } => {
             let all_repos = github::projects::get_all_pypi_data_repos(&github_token)?;
 
-            let client = github::get_client();
+            let client = github::get_client()?;
             let mut repos: Vec<_> = all_repos
                 .into_par_iter()
                 .progress()
@@ -398,7 +794,7 @@ fn main() -> anyhow::Result<()> {
             github_token,
             dry_run,
         } => {
-            let client = github::get_client();
+            let client = github::get_client()?;
             let mut all_repos = github::projects::get_all_pypi_data_repos(&github_token)?;
             all_repos.sort_by_key(|r| r.repo_index_integer());
             all_repos.reverse();
@@ -445,6 +841,8 @@ fn main() -> anyhow::Result<()> {
                         url: row.get(2)?,
                         upload_time: row.get(3)?,
                         processed: false,
+                        removed: false,
+                        truncated: None,
                     })
                 })?
                 .map(|v| v.unwrap());
@@ -604,64 +1002,150 @@ fn main() -> anyhow::Result<()> {
         //         sleep(Duration::from_secs(4));
         //     }
         // }
+        Commands::BuildDataset {
+            input_dir,
+            output_dir,
+            partition_by,
+        } => {
+            let summary = dataset::build_dataset(&input_dir, &output_dir, partition_by)?;
+            println!(
+                "Wrote {} partitions, removed {}, {} unchanged",
+                summary.written, summary.removed, summary.unchanged
+            );
+        }
+        Commands::Query {
+            dataset,
+            sql,
+            filters,
+            limit,
+            format,
+            output,
+        } => {
+            let df = dataset::scan_dataset(&dataset)?;
+            let df = query::run_query(df, &filters, sql.as_deref(), limit)?.collect()?;
+            query::write_results(df, format, output.as_deref())?;
+        }
+        Commands::Locate {
+            query,
+            index_dir,
+            refresh,
+            dataset,
+            github_token,
+        } => {
+            let query = locate::LocateQuery::parse(&query);
+            let indexes =
+                locate::load_indexes(index_dir.as_deref(), github_token.as_deref(), refresh)?;
+            let dataset = dataset
+                .map(|path| dataset::scan_dataset(&path))
+                .transpose()?;
+            let locations = locate::locate(&query, &indexes, dataset)?;
+            if locations.is_empty() {
+                eprintln!("Not found: {query:?}");
+            }
+            for location in locations {
+                println!(
+                    "{} {} {}",
+                    location.repository,
+                    location.package.identifier(),
+                    location.status()
+                );
+                println!("  {}", location.url());
+                for path in &location.paths {
+                    println!("  {path}");
+                }
+            }
+        }
+        Commands::Cat {
+            dataset,
+            selector,
+            repositories_dir,
+            github_token,
+        } => {
+            let locations = export::resolve(dataset::scan_dataset(&dataset)?, &selector)?;
+            let mut fetcher = export::BlobFetcher::new(repositories_dir, github_token);
+            export::cat(&locations, &mut fetcher)?;
+        }
+        Commands::Export {
+            dataset,
+            output_dir,
+            selector,
+            repositories_dir,
+            github_token,
+        } => {
+            let locations = export::resolve(dataset::scan_dataset(&dataset)?, &selector)?;
+            let mut fetcher = export::BlobFetcher::new(repositories_dir, github_token);
+            let written = export::export(&locations, &mut fetcher, &output_dir)?;
+            println!("Wrote {written} of {} files", locations.len());
+        }
+        Commands::Sync {
+            mirror_dir,
+            filter,
+            jobs,
+            github_token,
+        } => {
+            let repos = github::projects::get_all_pypi_data_repos(&github_token)?;
+            let summary = mirror::sync(&mirror_dir, &repos, filter, jobs)?;
+            println!(
+                "Cloned {}, updated {}, {} unchanged, {} without a code branch",
+                summary.cloned, summary.updated, summary.unchanged, summary.empty
+            );
+            for (name, error) in &summary.failed {
+                eprintln!("Failed to sync {name}: {error}");
+            }
+            if !summary.failed.is_empty() {
+                return Err(anyhow::anyhow!(
+                    "{} repositories failed to sync",
+                    summary.failed.len()
+                ));
+            }
+        }
+        Commands::Catalog {
+            output_file,
+            datasets_dir,
+            compress,
+            github_token,
+        } => {
+            let repo_status = github::status::get_status(&github_token, false, None)?;
+            let catalog =
+                catalog::build_catalog(repo_status, &github_token, datasets_dir.as_deref())?;
+            let output = BufWriter::new(File::create(&output_file)?);
+            if compress {
+                let mut output = ZlibEncoder::new(output, Compression::best());
+                serde_json::to_writer_pretty(&mut output, &catalog)?;
+                output.finish()?.flush()?;
+            } else {
+                let mut output = output;
+                serde_json::to_writer_pretty(&mut output, &catalog)?;
+                output.flush()?;
+            }
+            println!(
+                "Wrote {} repositories to {}",
+                catalog.repositories.len(),
+                output_file.display()
+            );
+        }
         Commands::GetAllIndexes {
             output_dir,
+            assets,
+            jobs,
+            retries,
             github_token,
         } => {
-            std::fs::create_dir_all(&output_dir)?;
             let all_repos = github::projects::get_all_pypi_data_repos(&github_token)?;
-            // let client = github::get_client();
-            let res: Vec<Result<_, _>> = all_repos
-                .into_par_iter()
-                .progress()
-                .map(|repo| {
-                    let output_path = output_dir.join(format!("{}.parquet", repo.name));
-                    let etag_path = output_dir.join(format!("{}.etag", repo.name));
-                    // let mut output_file =
-                    //     std::io::BufWriter::new(std::fs::File::create(&output_path).unwrap());
-                    let url = format!(
-                        "https://github.com/pypi-data/{}/releases/download/latest/dataset.parquet",
-                        repo.name
-                    );
-                    let result = duct::cmd!(
-                        "curl",
-                        &url,
-                        "-o",
-                        &output_path,
-                        "--silent",
-                        "--fail",
-                        "--retry",
-                        "5",
-                        "--retry-delay",
-                        "3",
-                        "--location",
-                        "-w",
-                        "%{http_code}",
-                        "--remote-time",
-                        "--remove-on-error",
-                        "--etag-compare",
-                        &etag_path,
-                        "--etag-save",
-                        &etag_path
-                    )
-                    .unchecked()
-                    .stdout_capture()
-                    .stderr_null()
-                    .run()?;
-
-                    let stdout = std::str::from_utf8(&result.stdout)?;
-
-                    match (result.status.success(), stdout) {
-                        (true, _) => Ok(()),
-                        (false, "404") => Ok(()),
-                        (false, status) => Err(anyhow::anyhow!(
-                            "Failed to download {url} with status {status}"
-                        )),
-                    }
-                })
-                .collect();
-            for item in res {
-                item?;
+            let downloader = download::Downloader::new(&output_dir, retries)?;
+            let summary = downloader.download_all(&all_repos, &assets, &github_token, jobs)?;
+            println!(
+                "Updated {}, {} unchanged, {} missing",
+                summary.updated, summary.unchanged, summary.missing
+            );
+            for (name, error) in &summary.failed {
+                eprintln!("Failed to download {name}: {error}");
+            }
+            if !summary.failed.is_empty() {
+                return Err(anyhow::anyhow!(
+                    "{} repositories failed to download",
+                    summary.failed.len()
+                ));
             }
         }
         Commands::DebugPackage { url, debug_index } => {
@@ -669,21 +1153,31 @@ fn main() -> anyhow::Result<()> {
                 true => Box::new(std::io::sink()),
                 false => Box::new(std::io::stdout()),
             };
+            let package = RepositoryPackage::fake_from_url(url);
             let writer = GitFastImporter::new(
                 std::io::BufWriter::new(out),
-                1,
+                std::slice::from_ref(&package),
                 "code".to_string(),
                 true,
                 true,
+                CommitGrouping::Package,
+                None,
             );
-            let agent = ureq::agent();
-            let package = RepositoryPackage::fake_from_url(url);
-            let index = crate::extract::download_package(agent, &package, &writer).unwrap();
+            let client = http::client()?;
+            let index = crate::extract::download_package(
+                client,
+                &package,
+                &writer,
+                ExtractOptions::default(),
+            )
+            .unwrap();
             if debug_index {
                 eprintln!("Index: {:#?}", index.items);
+                eprintln!("Metadata: {:#?}", index.metadata);
+                eprintln!("Analysis: {:#?}", index.analysis);
                 let mut index_writer =
                     crate::data::RepositoryFileIndexWriter::new(Path::new("index.parquet"));
-                index_writer.write_index(index);
+                index_writer.write_index(index)?;
                 index_writer.finish()?;
             }
         }
@@ -692,6 +1186,8 @@ fn main() -> anyhow::Result<()> {
             filter_name,
             no_import,
             skip_contents,
+            output_dir,
+            output_tar,
         } => {
             let current_path = std::env::current_exe()?;
             let repository_dir = tempdir::TempDir::new("pypi-data")?;
@@ -700,7 +1196,7 @@ fn main() -> anyhow::Result<()> {
             match url::Url::parse(&index_file_or_url) {
                 Ok(v) => {
                     println!("Downloading from {v}");
-                    let mut reader = ureq::request_url("GET", &v).call()?.into_reader();
+                    let mut reader = http::client()?.request_url("GET", &v).call()?.into_reader();
                     let mut output =
                         BufWriter::new(std::fs::File::create(tmp_path.join("index.json"))?);
                     std::io::copy(&mut reader, &mut output)?;
@@ -729,8 +1225,22 @@ fn main() -> anyhow::Result<()> {
             if skip_contents {
                 args.push("--skip-contents".to_string());
             }
+            args.extend(http::client()?.options().to_args());
+            let has_sink = output_dir.is_some() || output_tar.is_some();
+            if let Some(output_dir) = output_dir {
+                args.push(format!(
+                    "--output-dir={}",
+                    std::path::absolute(output_dir)?.display()
+                ));
+            }
+            if let Some(output_tar) = output_tar {
+                args.push(format!(
+                    "--output-tar={}",
+                    std::path::absolute(output_tar)?.display()
+                ));
+            }
 
-            if no_import {
+            if no_import || has_sink {
                 let stdout_file = File::create(tmp_path.join("log.txt"))?;
                 duct::cmd(current_path, args)
                     .stdout_file(stdout_file)
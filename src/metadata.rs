@@ -0,0 +1,623 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use itertools::Itertools;
+use polars::prelude::*;
+use serde::{Deserialize, Serialize};
+use tracing::{event, Level};
+
+use crate::data::{string_list_column, StreamingParquetWriter};
+use crate::repository::package::RepositoryPackage;
+
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum MetadataSource {
+    /// `*.dist-info/METADATA` in wheels, `PKG-INFO` in sdists and eggs.
+    CoreMetadata,
+    SetupCfg,
+    PyProject,
+}
+
+impl From<MetadataSource> for &'static str {
+    fn from(val: MetadataSource) -> Self {
+        match val {
+            MetadataSource::CoreMetadata => "core-metadata",
+            MetadataSource::SetupCfg => "setup.cfg",
+            MetadataSource::PyProject => "pyproject.toml",
+        }
+    }
+}
+
+impl MetadataSource {
+    /// The metadata file a path relative to the archive root contains, if any.
+    pub fn from_path(normalized_path: &str) -> Option<Self> {
+        match normalized_path.split('/').collect_vec().as_slice() {
+            [dir, "METADATA"] if dir.ends_with(".dist-info") => Some(Self::CoreMetadata),
+            ["PKG-INFO"] | ["EGG-INFO", "PKG-INFO"] => Some(Self::CoreMetadata),
+            ["setup.cfg"] => Some(Self::SetupCfg),
+            ["pyproject.toml"] => Some(Self::PyProject),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct PackageMetadata {
+    pub sources: Vec<MetadataSource>,
+    pub requires_python: Option<String>,
+    pub requires_dist: Vec<String>,
+    pub license: Option<String>,
+    pub classifiers: Vec<String>,
+    pub author: Option<String>,
+    pub author_email: Option<String>,
+    /// `label, url` pairs, in the format of the `Project-URL` field.
+    pub project_urls: Vec<String>,
+    pub build_backend: Option<String>,
+}
+
+impl PackageMetadata {
+    /// Fills fields missing from `self` with the ones from `other`.
+    fn merge(mut self, other: PackageMetadata) -> Self {
+        self.sources.extend(other.sources);
+        self.requires_python = self.requires_python.or(other.requires_python);
+        self.license = self.license.or(other.license);
+        self.author = self.author.or(other.author);
+        self.author_email = self.author_email.or(other.author_email);
+        self.build_backend = self.build_backend.or(other.build_backend);
+        if self.requires_dist.is_empty() {
+            self.requires_dist = other.requires_dist;
+        }
+        if self.classifiers.is_empty() {
+            self.classifiers = other.classifiers;
+        }
+        if self.project_urls.is_empty() {
+            self.project_urls = other.project_urls;
+        }
+        self
+    }
+}
+
+// Older tools write `UNKNOWN` for fields that weren't given.
+fn non_empty(value: &str) -> Option<String> {
+    let value = value.trim();
+    (!value.is_empty() && value != "UNKNOWN").then(|| value.to_string())
+}
+
+fn non_empty_lines(value: &str) -> impl Iterator<Item = String> + '_ {
+    value.lines().filter_map(non_empty)
+}
+
+/// Parses the email-style headers of a `METADATA` or `PKG-INFO` file. The body holds the
+/// description and is ignored.
+fn parse_core_metadata(data: &str) -> PackageMetadata {
+    let mut headers: Vec<(&str, String)> = vec![];
+    for line in data.lines() {
+        if line.is_empty() {
+            break;
+        }
+        if line.starts_with([' ', '\t']) {
+            if let Some((_, value)) = headers.last_mut() {
+                value.push('\n');
+                value.push_str(line.trim());
+            }
+        } else if let Some((key, value)) = line.split_once(':') {
+            headers.push((key, value.trim().to_string()));
+        }
+    }
+
+    let mut metadata = PackageMetadata {
+        sources: vec![MetadataSource::CoreMetadata],
+        ..Default::default()
+    };
+    let mut license_expression = None;
+    for (key, value) in headers {
+        match key.to_ascii_lowercase().as_str() {
+            "requires-python" => metadata.requires_python = non_empty(&value),
+            "requires-dist" => metadata.requires_dist.extend(non_empty(&value)),
+            "license-expression" => license_expression = non_empty(&value),
+            "license" => metadata.license = non_empty(&value),
+            "classifier" => metadata.classifiers.extend(non_empty(&value)),
+            "author" => metadata.author = non_empty(&value),
+            "author-email" => metadata.author_email = non_empty(&value),
+            "project-url" => metadata.project_urls.extend(non_empty(&value)),
+            "home-page" => metadata
+                .project_urls
+                .extend(non_empty(&value).map(|url| format!("Homepage, {url}"))),
+            _ => {}
+        }
+    }
+    metadata.license = license_expression.or(metadata.license);
+    metadata
+}
+
+/// Parses the declarative setuptools configuration. Values can continue over indented lines.
+fn parse_setup_cfg(data: &str) -> PackageMetadata {
+    let mut values: Vec<(String, String, String)> = vec![];
+    let mut section = String::new();
+    for line in data.lines() {
+        let trimmed = line.trim();
+        if trimmed.is_empty() || trimmed.starts_with(['#', ';']) {
+            continue;
+        }
+        if line.starts_with([' ', '\t']) {
+            if let Some((_, _, value)) = values.last_mut() {
+                value.push('\n');
+                value.push_str(trimmed);
+            }
+        } else if let Some(name) = trimmed.strip_prefix('[').and_then(|s| s.strip_suffix(']')) {
+            section = name.trim().to_ascii_lowercase();
+        } else if let Some((key, value)) = trimmed.split_once(['=', ':']) {
+            let key = key.trim().to_ascii_lowercase().replace('-', "_");
+            values.push((section.clone(), key, value.trim().to_string()));
+        }
+    }
+
+    let mut metadata = PackageMetadata {
+        sources: vec![MetadataSource::SetupCfg],
+        ..Default::default()
+    };
+    for (section, key, value) in values {
+        match (section.as_str(), key.as_str()) {
+            ("options", "python_requires") => metadata.requires_python = non_empty(&value),
+            ("options", "install_requires") => {
+                metadata.requires_dist.extend(non_empty_lines(&value))
+            }
+            ("metadata", "license") => metadata.license = non_empty(&value),
+            ("metadata", "classifiers") => metadata.classifiers.extend(non_empty_lines(&value)),
+            ("metadata", "author") => metadata.author = non_empty(&value),
+            ("metadata", "author_email") => metadata.author_email = non_empty(&value),
+            ("metadata", "url" | "home_page") => metadata
+                .project_urls
+                .extend(non_empty(&value).map(|url| format!("Homepage, {url}"))),
+            ("metadata", "project_urls") => {
+                metadata
+                    .project_urls
+                    .extend(non_empty_lines(&value).filter_map(|line| {
+                        let (label, url) = line.split_once('=')?;
+                        Some(format!("{}, {}", label.trim(), url.trim()))
+                    }))
+            }
+            _ => {}
+        }
+    }
+    metadata
+}
+
+#[derive(Deserialize)]
+struct PyProject {
+    #[serde(rename = "build-system")]
+    build_system: Option<BuildSystem>,
+    project: Option<Project>,
+}
+
+#[derive(Deserialize)]
+struct BuildSystem {
+    #[serde(rename = "build-backend")]
+    build_backend: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Project {
+    #[serde(rename = "requires-python")]
+    requires_python: Option<String>,
+    #[serde(default)]
+    dependencies: Vec<String>,
+    license: Option<ProjectLicense>,
+    #[serde(default)]
+    classifiers: Vec<String>,
+    #[serde(default)]
+    authors: Vec<ProjectAuthor>,
+    #[serde(default)]
+    urls: BTreeMap<String, String>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ProjectLicense {
+    Expression(String),
+    Table { text: Option<String> },
+}
+
+#[derive(Deserialize)]
+struct ProjectAuthor {
+    name: Option<String>,
+    email: Option<String>,
+}
+
+fn parse_pyproject(data: &str) -> Result<PackageMetadata, toml::de::Error> {
+    let pyproject: PyProject = toml::from_str(data)?;
+    let mut metadata = PackageMetadata {
+        sources: vec![MetadataSource::PyProject],
+        build_backend: pyproject.build_system.and_then(|b| b.build_backend),
+        ..Default::default()
+    };
+    if let Some(project) = pyproject.project {
+        metadata.requires_python = project.requires_python;
+        metadata.requires_dist = project.dependencies;
+        metadata.license = match project.license {
+            Some(ProjectLicense::Expression(expression)) => Some(expression),
+            Some(ProjectLicense::Table { text }) => text,
+            None => None,
+        };
+        metadata.classifiers = project.classifiers;
+        let names = project
+            .authors
+            .iter()
+            .filter_map(|a| a.name.as_deref())
+            .join(", ");
+        let emails = project
+            .authors
+            .iter()
+            .filter_map(|a| a.email.as_deref())
+            .join(", ");
+        metadata.author = non_empty(&names);
+        metadata.author_email = non_empty(&emails);
+        metadata.project_urls = project
+            .urls
+            .into_iter()
+            .map(|(label, url)| format!("{label}, {url}"))
+            .collect();
+    }
+    Ok(metadata)
+}
+
+/// Collects the metadata files of a package as its contents are extracted.
+#[derive(Default)]
+pub struct MetadataCollector {
+    core: Option<PackageMetadata>,
+    setup_cfg: Option<PackageMetadata>,
+    pyproject: Option<PackageMetadata>,
+}
+
+impl MetadataCollector {
+    pub fn add(&mut self, normalized_path: &str, data: &[u8]) {
+        let Some(source) = MetadataSource::from_path(normalized_path) else {
+            return;
+        };
+        let data = String::from_utf8_lossy(data);
+        let (slot, parsed) = match source {
+            MetadataSource::CoreMetadata => (&mut self.core, Some(parse_core_metadata(&data))),
+            MetadataSource::SetupCfg => (&mut self.setup_cfg, Some(parse_setup_cfg(&data))),
+            MetadataSource::PyProject => match parse_pyproject(&data) {
+                Ok(m) => (&mut self.pyproject, Some(m)),
+                Err(e) => {
+                    event!(Level::DEBUG, "Invalid {normalized_path}: {e}");
+                    (&mut self.pyproject, None)
+                }
+            },
+        };
+        if slot.is_none() {
+            *slot = parsed;
+        }
+    }
+
+    /// Combines the parsed files, preferring the core metadata over `pyproject.toml` over
+    /// `setup.cfg`.
+    pub fn finish(self) -> Option<PackageMetadata> {
+        [self.core, self.pyproject, self.setup_cfg]
+            .into_iter()
+            .flatten()
+            .reduce(PackageMetadata::merge)
+    }
+}
+
+pub struct PackageMetadataWriter {
+    writer: StreamingParquetWriter,
+}
+
+impl PackageMetadataWriter {
+    pub fn new(path: &Path) -> Self {
+        let schema = metadata_dataframe(&[], &[]).unwrap().schema().clone();
+        Self {
+            writer: StreamingParquetWriter::new(path, schema),
+        }
+    }
+
+    pub fn write_metadata(
+        &mut self,
+        package: &RepositoryPackage,
+        metadata: PackageMetadata,
+    ) -> anyhow::Result<()> {
+        self.writer
+            .write(metadata_dataframe(&[package], &[metadata])?)
+    }
+
+    pub fn finish(self) -> anyhow::Result<()> {
+        self.writer.finish()
+    }
+}
+
+fn metadata_dataframe(
+    packages: &[&RepositoryPackage],
+    metadata: &[PackageMetadata],
+) -> PolarsResult<DataFrame> {
+    let columns = vec![
+        Column::new(
+            "project_name".into(),
+            packages
+                .iter()
+                .map(|p| p.project_name.as_str())
+                .collect_vec(),
+        ),
+        Column::new(
+            "project_version".into(),
+            packages
+                .iter()
+                .map(|p| p.project_version.as_str())
+                .collect_vec(),
+        ),
+        Column::new(
+            "project_release".into(),
+            packages.iter().map(|p| p.package_filename()).collect_vec(),
+        ),
+        DatetimeChunked::from_naive_datetime(
+            "uploaded_on".into(),
+            packages.iter().map(|p| p.upload_time.naive_utc()),
+            TimeUnit::Milliseconds,
+        )
+        .into_column(),
+        string_list_column(
+            "metadata_sources",
+            metadata.iter().map(|m| {
+                m.sources
+                    .iter()
+                    .map(|s| <&str>::from(*s).to_string())
+                    .collect()
+            }),
+        ),
+        Column::new(
+            "requires_python".into(),
+            metadata
+                .iter()
+                .map(|m| m.requires_python.as_deref())
+                .collect_vec(),
+        ),
+        string_list_column(
+            "requires_dist",
+            metadata.iter().map(|m| m.requires_dist.clone()),
+        ),
+        Column::new(
+            "license".into(),
+            metadata.iter().map(|m| m.license.as_deref()).collect_vec(),
+        ),
+        string_list_column(
+            "classifiers",
+            metadata.iter().map(|m| m.classifiers.clone()),
+        ),
+        Column::new(
+            "author".into(),
+            metadata.iter().map(|m| m.author.as_deref()).collect_vec(),
+        ),
+        Column::new(
+            "author_email".into(),
+            metadata
+                .iter()
+                .map(|m| m.author_email.as_deref())
+                .collect_vec(),
+        ),
+        string_list_column(
+            "project_urls",
+            metadata.iter().map(|m| m.project_urls.clone()),
+        ),
+        Column::new(
+            "build_backend".into(),
+            metadata
+                .iter()
+                .map(|m| m.build_backend.as_deref())
+                .collect_vec(),
+        ),
+    ];
+    DataFrame::new(columns)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PKG_INFO: &str = "Metadata-Version: 2.4
+Name: requests
+Version: 2.32.3
+Summary: Python HTTP for Humans.
+Home-page: https://requests.readthedocs.io
+Author: Kenneth Reitz
+Author-email: me@kennethreitz.org
+License: Apache-2.0
+License-Expression: Apache-2.0
+Project-URL: Source, https://github.com/psf/requests
+Classifier: Programming Language :: Python :: 3
+Classifier: License :: OSI Approved :: Apache Software License
+Requires-Python: >=3.8
+Description-Content-Type: text/markdown
+Requires-Dist: charset_normalizer<4,>=2
+Requires-Dist: PySocks!=1.5.7,>=1.5.6; extra == \"socks\"
+Description: A long description
+        that continues over indented lines
+
+Classifier: Not a header
+";
+
+    #[test]
+    fn core_metadata() {
+        let metadata = parse_core_metadata(PKG_INFO);
+        assert_eq!(metadata.sources, [MetadataSource::CoreMetadata]);
+        assert_eq!(metadata.requires_python.as_deref(), Some(">=3.8"));
+        assert_eq!(
+            metadata.requires_dist,
+            [
+                "charset_normalizer<4,>=2",
+                "PySocks!=1.5.7,>=1.5.6; extra == \"socks\""
+            ]
+        );
+        assert_eq!(metadata.license.as_deref(), Some("Apache-2.0"));
+        assert_eq!(
+            metadata.classifiers,
+            [
+                "Programming Language :: Python :: 3",
+                "License :: OSI Approved :: Apache Software License"
+            ]
+        );
+        assert_eq!(metadata.author.as_deref(), Some("Kenneth Reitz"));
+        assert_eq!(
+            metadata.author_email.as_deref(),
+            Some("me@kennethreitz.org")
+        );
+        assert_eq!(
+            metadata.project_urls,
+            [
+                "Homepage, https://requests.readthedocs.io",
+                "Source, https://github.com/psf/requests"
+            ]
+        );
+    }
+
+    #[test]
+    fn core_metadata_unknown_values() {
+        let metadata = parse_core_metadata(
+            "Metadata-Version: 1.0\nName: old\nVersion: 0.1\nLicense: UNKNOWN\nAuthor: UNKNOWN\n",
+        );
+        assert_eq!(metadata.license, None);
+        assert_eq!(metadata.author, None);
+    }
+
+    #[test]
+    fn setup_cfg() {
+        let metadata = parse_setup_cfg(
+            "[metadata]
+name = example
+author = Jane Doe
+author-email = jane@example.com
+license = MIT
+url = https://example.com
+classifiers =
+    Programming Language :: Python :: 3
+    # A comment between values
+    Operating System :: OS Independent
+project_urls =
+    Source = https://github.com/example/example
+
+[options]
+python_requires = >=3.7
+install_requires =
+    requests>=2
+    click
+",
+        );
+        assert_eq!(metadata.sources, [MetadataSource::SetupCfg]);
+        assert_eq!(metadata.requires_python.as_deref(), Some(">=3.7"));
+        assert_eq!(metadata.requires_dist, ["requests>=2", "click"]);
+        assert_eq!(metadata.license.as_deref(), Some("MIT"));
+        assert_eq!(metadata.author.as_deref(), Some("Jane Doe"));
+        assert_eq!(metadata.author_email.as_deref(), Some("jane@example.com"));
+        assert_eq!(
+            metadata.classifiers,
+            [
+                "Programming Language :: Python :: 3",
+                "Operating System :: OS Independent"
+            ]
+        );
+        assert_eq!(
+            metadata.project_urls,
+            [
+                "Homepage, https://example.com",
+                "Source, https://github.com/example/example"
+            ]
+        );
+    }
+
+    #[test]
+    fn pyproject() {
+        let metadata = parse_pyproject(
+            r#"
+[build-system]
+requires = ["hatchling"]
+build-backend = "hatchling.build"
+
+[project]
+name = "example"
+requires-python = ">=3.9"
+dependencies = ["httpx>=0.27", "rich"]
+license = { text = "BSD-3-Clause" }
+classifiers = ["Framework :: Pytest"]
+authors = [
+    { name = "Jane Doe", email = "jane@example.com" },
+    { name = "John Doe" },
+]
+
+[project.urls]
+Homepage = "https://example.com"
+Changelog = "https://example.com/changes"
+"#,
+        )
+        .unwrap();
+        assert_eq!(metadata.sources, [MetadataSource::PyProject]);
+        assert_eq!(metadata.build_backend.as_deref(), Some("hatchling.build"));
+        assert_eq!(metadata.requires_python.as_deref(), Some(">=3.9"));
+        assert_eq!(metadata.requires_dist, ["httpx>=0.27", "rich"]);
+        assert_eq!(metadata.license.as_deref(), Some("BSD-3-Clause"));
+        assert_eq!(metadata.classifiers, ["Framework :: Pytest"]);
+        assert_eq!(metadata.author.as_deref(), Some("Jane Doe, John Doe"));
+        assert_eq!(metadata.author_email.as_deref(), Some("jane@example.com"));
+        assert_eq!(
+            metadata.project_urls,
+            [
+                "Changelog, https://example.com/changes",
+                "Homepage, https://example.com"
+            ]
+        );
+    }
+
+    #[test]
+    fn pyproject_license_expression_and_invalid_toml() {
+        let metadata = parse_pyproject("[project]\nlicense = \"MIT OR Apache-2.0\"\n").unwrap();
+        assert_eq!(metadata.license.as_deref(), Some("MIT OR Apache-2.0"));
+        assert!(parse_pyproject("[project\nname = ").is_err());
+    }
+
+    #[test]
+    fn metadata_paths() {
+        let cases = [
+            (
+                "requests-2.32.3.dist-info/METADATA",
+                Some(MetadataSource::CoreMetadata),
+            ),
+            ("PKG-INFO", Some(MetadataSource::CoreMetadata)),
+            ("EGG-INFO/PKG-INFO", Some(MetadataSource::CoreMetadata)),
+            ("setup.cfg", Some(MetadataSource::SetupCfg)),
+            ("pyproject.toml", Some(MetadataSource::PyProject)),
+            ("src/example.egg-info/PKG-INFO", None),
+            ("tests/pyproject.toml", None),
+        ];
+        for (path, expected) in cases {
+            assert_eq!(MetadataSource::from_path(path), expected, "{path}");
+        }
+    }
+
+    #[test]
+    fn collector_prefers_core_metadata() {
+        let mut collector = MetadataCollector::default();
+        collector.add("setup.cfg", b"[options]\ninstall_requires =\n    click\n");
+        collector.add(
+            "pyproject.toml",
+            b"[build-system]\nbuild-backend = \"setuptools.build_meta\"\n",
+        );
+        collector.add(
+            "PKG-INFO",
+            b"Metadata-Version: 2.1\nRequires-Python: >=3.8\n",
+        );
+        collector.add("tests/PKG-INFO", b"Requires-Python: >=2.7\n");
+        let metadata = collector.finish().unwrap();
+        assert_eq!(
+            metadata.sources,
+            [
+                MetadataSource::CoreMetadata,
+                MetadataSource::PyProject,
+                MetadataSource::SetupCfg
+            ]
+        );
+        assert_eq!(metadata.requires_python.as_deref(), Some(">=3.8"));
+        assert_eq!(metadata.requires_dist, ["click"]);
+        assert_eq!(
+            metadata.build_backend.as_deref(),
+            Some("setuptools.build_meta")
+        );
+    }
+}
@@ -0,0 +1,220 @@
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+use std::path::Path;
+use std::str::FromStr;
+
+use anyhow::bail;
+use chrono::{DateTime, Utc};
+use indicatif::ParallelProgressIterator;
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::github::projects::DataRepo;
+
+const STATE_FILE: &str = "sync-state.json";
+
+/// The only branch that is mirrored. It holds the extracted code.
+const BRANCH: &str = "code";
+
+/// Which objects new clones fetch up front. Partial clones fetch the rest from GitHub when git
+/// needs them, but lookups with `git2` only see what is stored locally.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum MirrorFilter {
+    Full,
+    Blobless,
+    Treeless,
+}
+
+impl FromStr for MirrorFilter {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "full" => Ok(MirrorFilter::Full),
+            "blobless" => Ok(MirrorFilter::Blobless),
+            "treeless" => Ok(MirrorFilter::Treeless),
+            _ => Err(format!(
+                "Unknown mirror filter {s}. Expected full, blobless or treeless"
+            )),
+        }
+    }
+}
+
+impl MirrorFilter {
+    fn spec(self) -> Option<&'static str> {
+        match self {
+            MirrorFilter::Full => None,
+            MirrorFilter::Blobless => Some("blob:none"),
+            MirrorFilter::Treeless => Some("tree:0"),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RepositoryState {
+    /// The filter the repository was cloned with. Changing it only affects new clones.
+    pub filter: MirrorFilter,
+    /// Commit of the `code` branch after the last successful fetch.
+    pub head: Option<String>,
+    pub fetched_at: Option<DateTime<Utc>>,
+    /// Error of the last fetch, cleared by the next successful one.
+    pub error: Option<String>,
+}
+
+/// Kept next to the clones, so that the mirror can be inspected without opening every one.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct MirrorState {
+    pub repositories: BTreeMap<String, RepositoryState>,
+}
+
+impl MirrorState {
+    fn load(mirror_dir: &Path) -> anyhow::Result<MirrorState> {
+        let path = mirror_dir.join(STATE_FILE);
+        if !path.exists() {
+            return Ok(MirrorState::default());
+        }
+        let reader = BufReader::new(File::open(&path)?);
+        Ok(serde_json::from_reader(reader)?)
+    }
+
+    fn save(&self, mirror_dir: &Path) -> anyhow::Result<()> {
+        let tmp_path = mirror_dir.join(format!("{STATE_FILE}.tmp"));
+        let writer = BufWriter::new(File::create(&tmp_path)?);
+        serde_json::to_writer_pretty(writer, self)?;
+        std::fs::rename(tmp_path, mirror_dir.join(STATE_FILE))?;
+        Ok(())
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct SyncSummary {
+    pub cloned: usize,
+    pub updated: usize,
+    pub unchanged: usize,
+    /// Repositories without a `code` branch yet.
+    pub empty: usize,
+    pub failed: Vec<(String, String)>,
+}
+
+// Messages are matched on, so they must not be translated.
+fn git(dir: &Path, args: &[&str]) -> anyhow::Result<String> {
+    let output = duct::cmd("git", args)
+        .dir(dir)
+        .env("LC_ALL", "C")
+        .stdout_capture()
+        .stderr_capture()
+        .unchecked()
+        .run()?;
+    if !output.status.success() {
+        bail!(
+            "git {}: {}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(String::from_utf8(output.stdout)?.trim().to_string())
+}
+
+/// Creates an empty bare repository for the mirror. It is set up in a temporary directory, so
+/// that an interrupted sync never leaves a repository without its remote behind.
+fn create_repository(path: &Path, url: &str, filter: MirrorFilter) -> anyhow::Result<()> {
+    let mut tmp_name = path.file_name().unwrap_or_default().to_os_string();
+    tmp_name.push(".tmp");
+    let tmp_path = path.with_file_name(tmp_name);
+    if tmp_path.exists() {
+        std::fs::remove_dir_all(&tmp_path)?;
+    }
+    std::fs::create_dir_all(&tmp_path)?;
+    git(&tmp_path, &["init", "--quiet", "--bare"])?;
+    git(&tmp_path, &["remote", "add", "origin", url])?;
+    git(
+        &tmp_path,
+        &["symbolic-ref", "HEAD", &format!("refs/heads/{BRANCH}")],
+    )?;
+    if let Some(spec) = filter.spec() {
+        git(&tmp_path, &["config", "remote.origin.promisor", "true"])?;
+        git(
+            &tmp_path,
+            &["config", "remote.origin.partialclonefilter", spec],
+        )?;
+    }
+    std::fs::rename(&tmp_path, path)?;
+    Ok(())
+}
+
+/// Fetches the `code` branch, returning its new head, or `None` if the branch doesn't exist yet.
+fn fetch_repository(path: &Path) -> anyhow::Result<Option<String>> {
+    let refspec = format!("+refs/heads/{BRANCH}:refs/heads/{BRANCH}");
+    match git(path, &["fetch", "--quiet", "--no-tags", "origin", &refspec]) {
+        Ok(_) => {}
+        Err(e) if e.to_string().contains("couldn't find remote ref") => return Ok(None),
+        Err(e) => return Err(e),
+    }
+    Ok(Some(git(
+        path,
+        &["rev-parse", &format!("refs/heads/{BRANCH}")],
+    )?))
+}
+
+/// Clones repositories that aren't in the mirror yet and fetches the `code` branch of the rest,
+/// `jobs` at a time. The state file is updated even if some repositories fail.
+pub fn sync(
+    mirror_dir: &Path,
+    repos: &[DataRepo],
+    filter: MirrorFilter,
+    jobs: usize,
+) -> anyhow::Result<SyncSummary> {
+    std::fs::create_dir_all(mirror_dir)?;
+    let mut state = MirrorState::load(mirror_dir)?;
+    let pool = rayon::ThreadPoolBuilder::new().num_threads(jobs).build()?;
+    let results = pool.install(|| {
+        repos
+            .par_iter()
+            .progress()
+            .map(|repo| {
+                let path = mirror_dir.join(&repo.name);
+                let created = !path.exists();
+                if created {
+                    let url = format!("https://github.com/pypi-data/{}.git", repo.name);
+                    create_repository(&path, &url, filter)?;
+                }
+                Ok((created, fetch_repository(&path)?))
+            })
+            .collect::<Vec<anyhow::Result<_>>>()
+    });
+
+    let mut summary = SyncSummary::default();
+    let now = Utc::now();
+    for (repo, result) in repos.iter().zip(results) {
+        let entry = state
+            .repositories
+            .entry(repo.name.clone())
+            .or_insert(RepositoryState {
+                filter,
+                head: None,
+                fetched_at: None,
+                error: None,
+            });
+        match result {
+            Ok((created, head)) => {
+                match &head {
+                    None => summary.empty += 1,
+                    Some(_) if created || entry.head.is_none() => summary.cloned += 1,
+                    Some(_) if head == entry.head => summary.unchanged += 1,
+                    Some(_) => summary.updated += 1,
+                }
+                entry.head = head;
+                entry.fetched_at = Some(now);
+                entry.error = None;
+            }
+            Err(e) => {
+                entry.error = Some(format!("{e:#}"));
+                summary.failed.push((repo.name.clone(), format!("{e:#}")));
+            }
+        }
+    }
+    state.save(mirror_dir)?;
+    Ok(summary)
+}
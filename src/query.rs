@@ -0,0 +1,221 @@
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::str::FromStr;
+
+use anyhow::bail;
+use chrono::NaiveDate;
+use git2::Oid;
+use polars::prelude::*;
+use polars::sql::SQLContext;
+use serde_json::{Map, Value};
+
+use crate::data::StreamingParquetWriter;
+use crate::repository::package::normalize_project_name;
+
+/// The name the dataset is registered under for SQL queries.
+pub const TABLE_NAME: &str = "files";
+
+#[derive(Clone, Copy, Debug)]
+pub enum OutputFormat {
+    Table,
+    Csv,
+    Json,
+    Parquet,
+}
+
+impl FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "table" => Ok(OutputFormat::Table),
+            "csv" => Ok(OutputFormat::Csv),
+            "json" => Ok(OutputFormat::Json),
+            "parquet" => Ok(OutputFormat::Parquet),
+            _ => Err(format!(
+                "Unknown output format {s}. Expected table, csv, json or parquet"
+            )),
+        }
+    }
+}
+
+/// Matches rows of a project, however its name was written.
+pub fn project_filter(project: &str) -> Expr {
+    col("project_name")
+        .str()
+        .to_lowercase()
+        .str()
+        .replace_all(lit("[_.]"), lit("-"), false)
+        .eq(lit(normalize_project_name(project)))
+}
+
+/// Filters that cover the common questions without writing SQL. They are applied before the
+/// SQL query, and pushed down to the parquet scan where possible.
+#[derive(Debug, Default, clap::Args)]
+pub struct Filters {
+    /// Only files of this project
+    #[clap(long)]
+    pub project: Option<String>,
+
+    /// Only files with a path matching this regex
+    #[clap(long)]
+    pub path: Option<String>,
+
+    /// Only files with this hash, in hex
+    #[clap(long)]
+    pub hash: Option<String>,
+
+    /// Only files uploaded in this year
+    #[clap(long)]
+    pub year: Option<i32>,
+
+    /// Only files uploaded on or after this date, e.g. 2024-01-31
+    #[clap(long)]
+    pub uploaded_after: Option<NaiveDate>,
+
+    /// Only files uploaded before this date
+    #[clap(long)]
+    pub uploaded_before: Option<NaiveDate>,
+}
+
+impl Filters {
+    pub fn apply(&self, mut df: LazyFrame) -> anyhow::Result<LazyFrame> {
+        if let Some(project) = &self.project {
+            df = df.filter(project_filter(project));
+        }
+        if let Some(path) = &self.path {
+            df = df.filter(col("path").str().contains(lit(path.as_str()), true));
+        }
+        if let Some(hash) = &self.hash {
+            if hash.len() != 40 {
+                bail!("Expected a 40 character hash, found {hash}");
+            }
+            let hash = Oid::from_str(hash)?;
+            df = df.filter(col("hash").eq(lit(hash.as_bytes().to_vec())));
+        }
+        if let Some(year) = self.year {
+            df = df.filter(col("uploaded_on").dt().year().eq(lit(year)));
+        }
+        if let Some(date) = self.uploaded_after {
+            df = df.filter(col("uploaded_on").gt_eq(lit(date.and_time(Default::default()))));
+        }
+        if let Some(date) = self.uploaded_before {
+            df = df.filter(col("uploaded_on").lt(lit(date.and_time(Default::default()))));
+        }
+        Ok(df)
+    }
+}
+
+/// Runs the filters and then the SQL query, if any, against the dataset.
+pub fn run_query(
+    dataset: LazyFrame,
+    filters: &Filters,
+    sql: Option<&str>,
+    limit: Option<usize>,
+) -> anyhow::Result<LazyFrame> {
+    let mut df = filters.apply(dataset)?;
+    if let Some(sql) = sql {
+        let mut context = SQLContext::new();
+        context.register(TABLE_NAME, df);
+        df = context.execute(sql)?;
+    }
+    if let Some(limit) = limit {
+        df = df.limit(limit as IdxSize);
+    }
+    Ok(df)
+}
+
+/// Makes the columns printable as text: hashes are shown in hex, and for CSV lists are joined
+/// with commas.
+fn to_text_columns(df: &mut DataFrame, join_lists: bool) -> anyhow::Result<()> {
+    let separator = StringChunked::new(PlSmallStr::EMPTY, [","]);
+    for name in df.get_column_names_owned() {
+        let column = df.column(&name)?;
+        let text = match column.dtype() {
+            DataType::Binary => column.binary()?.hex_encode(),
+            DataType::List(inner) if join_lists && **inner == DataType::String => {
+                column.list()?.lst_join(&separator, true)?.into_series()
+            }
+            _ => continue,
+        };
+        df.replace(&name, text.with_name(name.clone()))?;
+    }
+    Ok(())
+}
+
+fn json_value(value: AnyValue) -> Value {
+    match value {
+        AnyValue::Null => Value::Null,
+        AnyValue::Boolean(b) => b.into(),
+        AnyValue::String(s) => s.into(),
+        AnyValue::StringOwned(s) => s.as_str().into(),
+        AnyValue::UInt8(v) => v.into(),
+        AnyValue::UInt16(v) => v.into(),
+        AnyValue::UInt32(v) => v.into(),
+        AnyValue::UInt64(v) => v.into(),
+        AnyValue::Int8(v) => v.into(),
+        AnyValue::Int16(v) => v.into(),
+        AnyValue::Int32(v) => v.into(),
+        AnyValue::Int64(v) => v.into(),
+        AnyValue::Float32(v) => v.into(),
+        AnyValue::Float64(v) => v.into(),
+        AnyValue::List(series) => series.iter().map(json_value).collect(),
+        // Dates are written the way polars displays them, and enums as their category.
+        other => other.str_value().into(),
+    }
+}
+
+/// Writes one JSON object per row.
+fn write_json(df: &DataFrame, mut writer: impl Write) -> anyhow::Result<()> {
+    let columns = df.get_columns();
+    for row in 0..df.height() {
+        let object: Map<String, Value> = columns
+            .iter()
+            .map(|c| Ok((c.name().to_string(), json_value(c.get(row)?))))
+            .collect::<PolarsResult<_>>()?;
+        serde_json::to_writer(&mut writer, &object)?;
+        writeln!(writer)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// Writes the results to `output`, or stdout if there is no output file.
+pub fn write_results(
+    mut df: DataFrame,
+    format: OutputFormat,
+    output: Option<&Path>,
+) -> anyhow::Result<()> {
+    let open = || -> anyhow::Result<Box<dyn Write>> {
+        Ok(match output {
+            Some(path) => Box::new(BufWriter::new(File::create(path)?)),
+            None => Box::new(BufWriter::new(std::io::stdout().lock())),
+        })
+    };
+    match format {
+        OutputFormat::Table => {
+            to_text_columns(&mut df, false)?;
+            let mut writer = open()?;
+            writeln!(writer, "{df}")?;
+            writer.flush()?;
+        }
+        OutputFormat::Csv => {
+            to_text_columns(&mut df, true)?;
+            CsvWriter::new(open()?).finish(&mut df)?;
+        }
+        OutputFormat::Json => {
+            to_text_columns(&mut df, false)?;
+            write_json(&df, open()?)?;
+        }
+        OutputFormat::Parquet => {
+            let Some(path) = output else {
+                bail!("Parquet output needs an output file");
+            };
+            let mut writer = StreamingParquetWriter::new(path, df.schema().clone());
+            writer.write(df)?;
+            writer.finish()?;
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,170 @@
+use thiserror::Error;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum PackageType {
+    Wheel,
+    Sdist,
+    Egg,
+    /// Windows installers built by the long-deprecated `bdist_wininst` and `bdist_msi`.
+    WindowsInstaller,
+    Other,
+}
+
+impl From<PackageType> for &'static str {
+    fn from(val: PackageType) -> Self {
+        match val {
+            PackageType::Wheel => "wheel",
+            PackageType::Sdist => "sdist",
+            PackageType::Egg => "egg",
+            PackageType::WindowsInstaller => "windows-installer",
+            PackageType::Other => "other",
+        }
+    }
+}
+
+impl PackageType {
+    pub fn from_filename(filename: &str) -> Self {
+        let filename = filename.to_ascii_lowercase();
+        if filename.ends_with(".whl") {
+            PackageType::Wheel
+        } else if filename.ends_with(".egg") {
+            PackageType::Egg
+        } else if [".tar.gz", ".tar.bz2", ".tgz", ".zip", ".tar.xz", ".tar"]
+            .iter()
+            .any(|ext| filename.ends_with(ext))
+        {
+            PackageType::Sdist
+        } else if filename.ends_with(".exe") || filename.ends_with(".msi") {
+            PackageType::WindowsInstaller
+        } else {
+            PackageType::Other
+        }
+    }
+}
+
+#[derive(Error, Debug, PartialEq)]
+pub enum InvalidWheelFilename {
+    #[error("Expected 5 or 6 dash-separated parts, found {0}")]
+    WrongNumberOfParts(usize),
+
+    #[error("Empty part in filename")]
+    EmptyPart,
+
+    #[error("Build tag must start with a digit: {0}")]
+    InvalidBuildTag(String),
+}
+
+/// The components of a wheel filename:
+/// `{distribution}-{version}(-{build tag})?-{python tag}-{abi tag}-{platform tag}.whl`.
+/// Tags can be compressed sets such as `cp310.cp311`, which are split into their parts.
+#[derive(Debug, PartialEq)]
+pub struct WheelTags {
+    pub distribution: String,
+    pub version: String,
+    pub build_tag: Option<String>,
+    pub python_tags: Vec<String>,
+    pub abi_tags: Vec<String>,
+    pub platform_tags: Vec<String>,
+}
+
+impl WheelTags {
+    pub fn parse(filename: &str) -> Result<Self, InvalidWheelFilename> {
+        let stem = filename.strip_suffix(".whl").unwrap_or(filename);
+        let parts: Vec<&str> = stem.split('-').collect();
+        if parts.iter().any(|p| p.is_empty()) {
+            return Err(InvalidWheelFilename::EmptyPart);
+        }
+        let (distribution, version, build_tag, python, abi, platform) = match parts.as_slice() {
+            [distribution, version, python, abi, platform] => {
+                (distribution, version, None, python, abi, platform)
+            }
+            [distribution, version, build, python, abi, platform] => {
+                if !build.starts_with(|c: char| c.is_ascii_digit()) {
+                    return Err(InvalidWheelFilename::InvalidBuildTag(build.to_string()));
+                }
+                (distribution, version, Some(build), python, abi, platform)
+            }
+            _ => return Err(InvalidWheelFilename::WrongNumberOfParts(parts.len())),
+        };
+        let split = |tags: &str| tags.split('.').map(str::to_string).collect();
+        Ok(WheelTags {
+            distribution: distribution.to_string(),
+            version: version.to_string(),
+            build_tag: build_tag.map(|b| b.to_string()),
+            python_tags: split(python),
+            abi_tags: split(abi),
+            platform_tags: split(platform),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn package_types() {
+        let cases = [
+            ("numpy-2.0.0-cp312-cp312-win_amd64.whl", PackageType::Wheel),
+            ("Django-1.0.TAR.GZ", PackageType::Sdist),
+            ("foo-1.0.tgz", PackageType::Sdist),
+            ("foo-1.0.zip", PackageType::Sdist),
+            ("foo-1.0-py2.7.egg", PackageType::Egg),
+            ("foo-1.0.win32.exe", PackageType::WindowsInstaller),
+            ("foo-1.0.win-amd64.msi", PackageType::WindowsInstaller),
+            ("foo-1.0.rpm", PackageType::Other),
+        ];
+        for (filename, expected) in cases {
+            assert_eq!(PackageType::from_filename(filename), expected, "{filename}");
+        }
+    }
+
+    #[test]
+    fn wheel_tags() {
+        let tags = WheelTags::parse(
+            "baz-2.0-cp310.cp311-cp310-manylinux_2_17_aarch64.manylinux2014_aarch64.whl",
+        )
+        .unwrap();
+        assert_eq!(
+            tags,
+            WheelTags {
+                distribution: "baz".to_string(),
+                version: "2.0".to_string(),
+                build_tag: None,
+                python_tags: vec!["cp310".to_string(), "cp311".to_string()],
+                abi_tags: vec!["cp310".to_string()],
+                platform_tags: vec![
+                    "manylinux_2_17_aarch64".to_string(),
+                    "manylinux2014_aarch64".to_string()
+                ],
+            }
+        );
+    }
+
+    #[test]
+    fn wheel_build_tag() {
+        let tags = WheelTags::parse("foo-1.0-1a-py3-none-any.whl").unwrap();
+        assert_eq!(tags.build_tag.as_deref(), Some("1a"));
+        assert_eq!(tags.platform_tags, ["any"]);
+    }
+
+    #[test]
+    fn invalid_wheel_filenames() {
+        assert_eq!(
+            WheelTags::parse("baz-2.0-any.whl"),
+            Err(InvalidWheelFilename::WrongNumberOfParts(3))
+        );
+        assert_eq!(
+            WheelTags::parse("foo-1.0-a-b-py3-none-any.whl"),
+            Err(InvalidWheelFilename::WrongNumberOfParts(7))
+        );
+        assert_eq!(
+            WheelTags::parse("foo--py3-none-any.whl"),
+            Err(InvalidWheelFilename::EmptyPart)
+        );
+        assert_eq!(
+            WheelTags::parse("foo-1.0-dev-py3-none-any.whl"),
+            Err(InvalidWheelFilename::InvalidBuildTag("dev".to_string()))
+        );
+    }
+}
@@ -1,6 +1,7 @@
 use crate::repository::package::RepositoryPackage;
 use serde::{Deserialize, Serialize};
 use std::cmp::Ordering;
+use std::collections::HashMap;
 
 use std::fmt::{Display, Formatter};
 use std::fs::File;
@@ -129,13 +130,33 @@ impl RepositoryIndex {
     }
 
     pub fn mark_packages_as_processed(&mut self, packages: Vec<RepositoryPackage>) {
+        let processed: HashMap<_, _> = packages.into_iter().map(|p| (p.url.clone(), p)).collect();
         for package in self.packages.iter_mut() {
-            if packages.contains(package) {
-                package.set_processed(true)
+            if let Some(processed_package) = processed.get(&package.url) {
+                package.set_processed(true);
+                package.truncated = processed_package.truncated.clone();
             }
         }
     }
 
+    pub fn mark_packages_as_removed(
+        &mut self,
+        predicate: impl Fn(&RepositoryPackage) -> bool,
+    ) -> Vec<RepositoryPackage> {
+        let mut removed = vec![];
+        for package in self.packages.iter_mut() {
+            if !package.removed && predicate(package) {
+                removed.push(package.clone());
+                package.removed = true;
+            }
+        }
+        removed
+    }
+
+    pub fn removed_packages(&self) -> impl Iterator<Item = &RepositoryPackage> {
+        self.packages.iter().filter(|p| p.removed)
+    }
+
     pub fn into_packages(self) -> Vec<RepositoryPackage> {
         self.packages
     }
@@ -143,7 +164,7 @@ impl RepositoryIndex {
     pub fn unprocessed_packages(&mut self) -> Vec<RepositoryPackage> {
         self.packages
             .iter()
-            .filter(|p| !p.processed)
+            .filter(|p| !p.processed && !p.removed)
             .cloned()
             .collect()
     }
@@ -1,3 +1,4 @@
+pub mod filename;
 pub mod index;
 pub mod package;
 pub mod state;
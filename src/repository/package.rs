@@ -1,3 +1,4 @@
+use crate::repository::filename::{InvalidWheelFilename, PackageType, WheelTags};
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 use std::fmt::{Display, Formatter};
@@ -10,6 +11,10 @@ pub struct RepositoryPackage {
     pub url: Url,
     pub upload_time: DateTime<Utc>,
     pub processed: bool,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub removed: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub truncated: Option<String>,
 }
 
 impl RepositoryPackage {
@@ -38,6 +43,62 @@ impl RepositoryPackage {
         )
     }
 
+    pub fn normalized_project_name(&self) -> String {
+        normalize_project_name(&self.project_name)
+    }
+
+    /// Where the package is in its life cycle: removed, truncated, processed or pending.
+    pub fn status(&self) -> &'static str {
+        match (&self.truncated, self.removed) {
+            (_, true) => "removed",
+            (Some(_), _) => "truncated",
+            (None, _) if self.processed => "processed",
+            (None, _) => "pending",
+        }
+    }
+
+    pub fn package_type(&self) -> PackageType {
+        PackageType::from_filename(self.package_filename())
+    }
+
+    /// The tags of a wheel, or `None` for other package types.
+    pub fn wheel_tags(&self) -> Option<Result<WheelTags, InvalidWheelFilename>> {
+        match self.package_type() {
+            PackageType::Wheel => Some(WheelTags::parse(self.package_filename())),
+            _ => None,
+        }
+    }
+
+    // Stable location for every release of a project, so that consecutive releases replace
+    // each other and can be compared with `git diff`. Wheels built for different platforms are
+    // uploaded together, so each set of tags gets its own location.
+    pub fn project_prefix(&self) -> String {
+        let kind = <&str>::from(self.package_type());
+        match self.wheel_tags() {
+            Some(Ok(tags)) => format!(
+                "projects/{}/{kind}/{}-{}-{}/",
+                self.normalized_project_name(),
+                tags.python_tags.join("."),
+                tags.abi_tags.join("."),
+                tags.platform_tags.join(".")
+            ),
+            Some(Err(_)) => format!(
+                "projects/{}/{kind}/unknown/",
+                self.normalized_project_name()
+            ),
+            None => format!("projects/{}/{kind}/", self.normalized_project_name()),
+        }
+    }
+
+    // Sdists nest all of their contents under a `name-version/` directory named after the
+    // archive itself.
+    pub fn archive_root(&self) -> Option<&str> {
+        let filename = self.package_filename();
+        [".tar.gz", ".tar.bz2", ".tgz", ".zip"]
+            .iter()
+            .find_map(|ext| filename.strip_suffix(ext))
+    }
+
     pub fn fake_from_url(url: Url) -> Self {
         RepositoryPackage {
             project_name: "fake".to_string(),
@@ -45,6 +106,8 @@ impl RepositoryPackage {
             url,
             upload_time: Default::default(),
             processed: false,
+            removed: false,
+            truncated: None,
         }
     }
 }
@@ -54,3 +117,9 @@ impl Display for RepositoryPackage {
         write!(f, "{}", self.identifier())
     }
 }
+
+pub fn normalize_project_name(project_name: &str) -> String {
+    let mut name = project_name.replace(['_', '.'], "-");
+    name.make_ascii_lowercase();
+    name
+}
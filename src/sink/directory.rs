@@ -0,0 +1,88 @@
+use crate::repository::package::RepositoryPackage;
+use crate::sink::{is_safe_path, OutputSink};
+use std::collections::HashMap;
+use std::io;
+use std::io::ErrorKind;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use tracing::{event, Level};
+
+#[derive(Debug)]
+pub struct DirectorySink {
+    root: PathBuf,
+    current_node: usize,
+    pending: HashMap<usize, Vec<u8>>,
+    skip_contents: bool,
+}
+
+impl DirectorySink {
+    pub fn new(root: PathBuf, skip_contents: bool) -> io::Result<Mutex<Self>> {
+        std::fs::create_dir_all(&root)?;
+        Ok(Mutex::new(DirectorySink {
+            root,
+            current_node: 0,
+            pending: HashMap::new(),
+            skip_contents,
+        }))
+    }
+}
+
+impl OutputSink for DirectorySink {
+    fn add_file(&mut self, data: Vec<u8>) -> io::Result<usize> {
+        self.current_node += 1;
+        // The same placeholder `GitFastImporter` writes, so that the files are still listed.
+        let data = if self.skip_contents {
+            b"d".to_vec()
+        } else {
+            data
+        };
+        self.pending.insert(self.current_node, data);
+        Ok(self.current_node)
+    }
+
+    fn flush_commit(
+        &mut self,
+        package: &RepositoryPackage,
+        paths_to_nodes: Vec<(usize, String)>,
+    ) -> io::Result<Option<String>> {
+        for (node, path) in paths_to_nodes {
+            let data = self.pending.remove(&node).unwrap_or_default();
+            if !is_safe_path(&path) {
+                event!(Level::WARN, "Skipping unsafe path {path} in {package}");
+                continue;
+            }
+            let output_path = self.root.join(&path);
+            if let Some(parent) = output_path.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            std::fs::write(output_path, data)?;
+        }
+        Ok(None)
+    }
+
+    fn discard(&mut self, _package: &RepositoryPackage, nodes: Vec<usize>) -> io::Result<()> {
+        for node in nodes {
+            self.pending.remove(&node);
+        }
+        Ok(())
+    }
+
+    fn remove_paths(&mut self, name: &str, paths: Vec<String>) -> io::Result<()> {
+        for path in paths {
+            let path = path.trim_end_matches('/');
+            if !is_safe_path(path) {
+                event!(Level::WARN, "Skipping unsafe path {path} in {name}");
+                continue;
+            }
+            match std::fs::remove_dir_all(self.root.join(path)) {
+                Err(e) if e.kind() != ErrorKind::NotFound => return Err(e),
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,47 @@
+use crate::repository::package::RepositoryPackage;
+use std::io;
+use std::path::{Component, Path};
+
+pub mod directory;
+pub mod tar;
+
+/// Destination for the accepted contents of extracted packages. Files are added one at a time
+/// and identified by the returned node, then grouped together under their final paths when the
+/// package is complete.
+pub trait OutputSink {
+    fn add_file(&mut self, data: Vec<u8>) -> io::Result<usize>;
+
+    /// Returns the identifier of the package named in the subject of the commit that adds the
+    /// files, for sinks that commit them.
+    fn flush_commit(
+        &mut self,
+        package: &RepositoryPackage,
+        paths_to_nodes: Vec<(usize, String)>,
+    ) -> io::Result<Option<String>>;
+
+    /// Called for packages that won't be flushed, e.g. because they failed to download, so that
+    /// sinks grouping packages together don't wait for them.
+    fn skip_package(&mut self, _package: &RepositoryPackage) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Drop the files added for a package that failed after some of them were added, so that
+    /// sinks holding them until `flush_commit` don't keep them around.
+    fn discard(&mut self, _package: &RepositoryPackage, _nodes: Vec<usize>) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Remove everything below the given path prefixes, e.g. for a release deleted from PyPI.
+    fn remove_paths(&mut self, name: &str, paths: Vec<String>) -> io::Result<()>;
+
+    fn finish(&mut self) -> io::Result<()>;
+}
+
+// Git rejects paths that escape the repository, but a plain filesystem won't. Archives can contain
+// anything, so only allow paths made of normal components.
+pub fn is_safe_path(path: &str) -> bool {
+    !path.is_empty()
+        && Path::new(path)
+            .components()
+            .all(|c| matches!(c, Component::Normal(_)))
+}
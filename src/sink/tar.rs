@@ -0,0 +1,122 @@
+use crate::repository::package::RepositoryPackage;
+use crate::sink::{is_safe_path, OutputSink};
+use chrono::Utc;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io;
+use std::io::{BufWriter, ErrorKind, Write};
+use std::path::Path;
+use std::sync::Mutex;
+use tar::{Builder, Header};
+use tracing::{event, Level};
+
+enum TarWriter {
+    Plain(BufWriter<File>),
+    Zstd(zstd::Encoder<'static, BufWriter<File>>),
+}
+
+impl Write for TarWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            TarWriter::Plain(w) => w.write(buf),
+            TarWriter::Zstd(w) => w.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            TarWriter::Plain(w) => w.flush(),
+            TarWriter::Zstd(w) => w.flush(),
+        }
+    }
+}
+
+pub struct TarSink {
+    // Taken by `finish`, which needs ownership to complete the zstd frame.
+    builder: Option<Builder<TarWriter>>,
+    current_node: usize,
+    pending: HashMap<usize, Vec<u8>>,
+    skip_contents: bool,
+}
+
+impl TarSink {
+    pub fn new(path: &Path, skip_contents: bool) -> io::Result<Mutex<Self>> {
+        let writer = BufWriter::new(File::create(path)?);
+        let writer = match path.extension() {
+            Some(ext) if ext == "zst" => TarWriter::Zstd(zstd::Encoder::new(writer, 3)?),
+            _ => TarWriter::Plain(writer),
+        };
+        Ok(Mutex::new(TarSink {
+            builder: Some(Builder::new(writer)),
+            current_node: 0,
+            pending: HashMap::new(),
+            skip_contents,
+        }))
+    }
+
+    fn builder(&mut self) -> io::Result<&mut Builder<TarWriter>> {
+        self.builder
+            .as_mut()
+            .ok_or_else(|| io::Error::other("Tar archive already finished"))
+    }
+}
+
+impl OutputSink for TarSink {
+    fn add_file(&mut self, data: Vec<u8>) -> io::Result<usize> {
+        self.current_node += 1;
+        // The same placeholder `GitFastImporter` writes, so that the files are still listed.
+        let data = if self.skip_contents {
+            b"d".to_vec()
+        } else {
+            data
+        };
+        self.pending.insert(self.current_node, data);
+        Ok(self.current_node)
+    }
+
+    fn flush_commit(
+        &mut self,
+        package: &RepositoryPackage,
+        paths_to_nodes: Vec<(usize, String)>,
+    ) -> io::Result<Option<String>> {
+        let now = Utc::now().timestamp() as u64;
+        for (node, path) in paths_to_nodes {
+            let data = self.pending.remove(&node).unwrap_or_default();
+            if !is_safe_path(&path) {
+                event!(Level::WARN, "Skipping unsafe path {path} in {package}");
+                continue;
+            }
+            let mut header = Header::new_gnu();
+            header.set_size(data.len() as u64);
+            header.set_mode(0o644);
+            header.set_mtime(now);
+            self.builder()?
+                .append_data(&mut header, &path, data.as_slice())?;
+        }
+        Ok(None)
+    }
+
+    fn discard(&mut self, _package: &RepositoryPackage, nodes: Vec<usize>) -> io::Result<()> {
+        for node in nodes {
+            self.pending.remove(&node);
+        }
+        Ok(())
+    }
+
+    fn remove_paths(&mut self, _name: &str, _paths: Vec<String>) -> io::Result<()> {
+        Err(io::Error::new(
+            ErrorKind::Unsupported,
+            "Tar archives are append-only and cannot remove paths",
+        ))
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        let Some(builder) = self.builder.take() else {
+            return Ok(());
+        };
+        match builder.into_inner()? {
+            TarWriter::Plain(mut w) => w.flush(),
+            TarWriter::Zstd(w) => w.finish()?.flush(),
+        }
+    }
+}
@@ -50,10 +50,8 @@ pub fn create_repository_pages(
 
     let packages_by_name = processed_packages
         .map(|(idx, p)| {
-            let mut name = p.project_name.replace(['_', '.'], "-");
-            name.make_ascii_lowercase();
             (
-                name,
+                p.normalized_project_name(),
                 PackageWithIndex {
                     index: idx,
                     package_filename: p.package_filename().to_string(),
@@ -0,0 +1,343 @@
+use crate::analysis::PackageAnalysis;
+use crate::data::{IndexItem, PackageFileIndex};
+use crate::extract::{download_package, DownloadError, ExtractOptions};
+use crate::metadata::PackageMetadata;
+use crate::repository::package::RepositoryPackage;
+use crate::sink::OutputSink;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io;
+use std::io::{BufRead, BufReader, BufWriter, Read, Stdout, Write};
+use std::os::unix::process::{CommandExt, ExitStatusExt};
+use std::process::{Child, ChildStdin, ChildStdout, Command, Stdio};
+use std::sync::Mutex;
+use tracing::{event, Level};
+
+const MB: u64 = 1024 * 1024;
+
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize)]
+pub struct WorkerLimits {
+    pub memory_mb: Option<u64>,
+    pub cpu_seconds: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct WorkerRequest {
+    package: RepositoryPackage,
+    options: ExtractOptions,
+    cpu_seconds: Option<u64>,
+}
+
+// Messages sent from a worker to the parent over its stdout. `File` is followed by the raw
+// file contents. Every package ends with one of `Index`, `Missing`, `SkippedFormat` or `Error`.
+#[derive(Serialize, Deserialize)]
+enum WorkerMessage {
+    File {
+        len: usize,
+    },
+    Commit {
+        paths_to_nodes: Vec<(usize, String)>,
+    },
+    Index {
+        items: Vec<IndexItem>,
+        truncated: Option<String>,
+        metadata: Option<Box<PackageMetadata>>,
+        analysis: PackageAnalysis,
+    },
+    Missing,
+    SkippedFormat,
+    Error(String),
+}
+
+fn write_message<W: Write>(output: &mut W, message: &WorkerMessage) -> io::Result<()> {
+    serde_json::to_writer(&mut *output, message)?;
+    writeln!(output)
+}
+
+struct WorkerSink {
+    output: BufWriter<Stdout>,
+    current_node: usize,
+}
+
+impl OutputSink for WorkerSink {
+    fn add_file(&mut self, data: Vec<u8>) -> io::Result<usize> {
+        self.current_node += 1;
+        write_message(&mut self.output, &WorkerMessage::File { len: data.len() })?;
+        self.output.write_all(&data)?;
+        Ok(self.current_node)
+    }
+
+    fn flush_commit(
+        &mut self,
+        _package: &RepositoryPackage,
+        paths_to_nodes: Vec<(usize, String)>,
+    ) -> io::Result<Option<String>> {
+        write_message(&mut self.output, &WorkerMessage::Commit { paths_to_nodes })?;
+        Ok(None)
+    }
+
+    fn remove_paths(&mut self, _name: &str, _paths: Vec<String>) -> io::Result<()> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "Workers only add packages",
+        ))
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        self.output.flush()
+    }
+}
+
+/// Entry point of a worker process: reads packages from stdin, one JSON request per line,
+/// extracts them and streams the results back over stdout. Exits once stdin is closed.
+pub fn run_worker() -> anyhow::Result<()> {
+    let client = crate::http::client()?;
+    let sink = Mutex::new(WorkerSink {
+        output: BufWriter::new(io::stdout()),
+        current_node: 0,
+    });
+    for line in io::stdin().lock().lines() {
+        let request: WorkerRequest = serde_json::from_str(&line?)?;
+        if let Some(cpu_seconds) = request.cpu_seconds {
+            limit_cpu(cpu_seconds)?;
+        }
+        sink.lock().unwrap().current_node = 0;
+        let message =
+            match download_package(client.clone(), &request.package, &sink, request.options) {
+                Ok(index) => WorkerMessage::Index {
+                    items: index.items,
+                    truncated: index.truncated,
+                    metadata: index.metadata.map(Box::new),
+                    analysis: index.analysis,
+                },
+                Err(DownloadError::Missing) => WorkerMessage::Missing,
+                Err(DownloadError::SkippedFormat) => WorkerMessage::SkippedFormat,
+                Err(e) => WorkerMessage::Error(e.to_string()),
+            };
+        let mut sink = sink.lock().unwrap();
+        write_message(&mut sink.output, &message)?;
+        sink.finish()?;
+    }
+    Ok(())
+}
+
+fn set_memory_limit(memory_mb: u64) -> io::Result<()> {
+    let limit = (memory_mb * MB) as libc::rlim_t;
+    let rlimit = libc::rlimit {
+        rlim_cur: limit,
+        rlim_max: limit,
+    };
+    if unsafe { libc::setrlimit(libc::RLIMIT_AS, &rlimit) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+// CPU time adds up over every package a worker extracts, so the limit is moved forward to the
+// time used so far before each one.
+fn limit_cpu(seconds: u64) -> io::Result<()> {
+    let mut usage: libc::rusage = unsafe { std::mem::zeroed() };
+    let mut rlimit: libc::rlimit = unsafe { std::mem::zeroed() };
+    unsafe {
+        if libc::getrusage(libc::RUSAGE_SELF, &mut usage) != 0
+            || libc::getrlimit(libc::RLIMIT_CPU, &mut rlimit) != 0
+        {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    // Rounded up, as the limit is in whole seconds.
+    let used = (usage.ru_utime.tv_sec + usage.ru_stime.tv_sec + 1) as u64;
+    rlimit.rlim_cur = ((used + seconds) as libc::rlim_t).min(rlimit.rlim_max);
+    if unsafe { libc::setrlimit(libc::RLIMIT_CPU, &rlimit) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+fn describe_signal(signal: i32) -> &'static str {
+    match signal {
+        libc::SIGXCPU => "CPU time limit exceeded",
+        libc::SIGKILL => "killed, likely out of memory",
+        libc::SIGABRT => "aborted, likely a failed allocation or stack overflow",
+        libc::SIGSEGV => "segmentation fault, likely a stack overflow or the memory limit",
+        _ => "killed by signal",
+    }
+}
+
+struct Worker {
+    child: Child,
+    stdin: ChildStdin,
+    stdout: BufReader<ChildStdout>,
+}
+
+impl Worker {
+    fn spawn(limits: WorkerLimits) -> io::Result<Worker> {
+        let mut command = Command::new(std::env::current_exe()?);
+        command
+            .args(
+                crate::http::client()
+                    .map_err(io::Error::other)?
+                    .options()
+                    .to_args(),
+            )
+            .arg("extract-worker")
+            .args(
+                crate::analysis::rules::rules()
+                    .path()
+                    .map(|path| format!("--rules={}", path.display())),
+            )
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::inherit());
+        if let Some(memory_mb) = limits.memory_mb {
+            // Only setrlimit is called between fork and exec, which is async-signal-safe.
+            unsafe {
+                command.pre_exec(move || set_memory_limit(memory_mb));
+            }
+        }
+        let mut child = command.spawn()?;
+        Ok(Worker {
+            stdin: child.stdin.take().unwrap(),
+            stdout: BufReader::new(child.stdout.take().unwrap()),
+            child,
+        })
+    }
+
+    /// Sends a package to the worker and passes the files it extracts on to `output`. Returns
+    /// `None` if the worker died before finishing the package. After an error the worker is in
+    /// an unknown state and must not be reused.
+    fn extract<'a, S: OutputSink>(
+        &mut self,
+        request: &WorkerRequest,
+        package: &'a RepositoryPackage,
+        output: &Mutex<S>,
+    ) -> Result<Option<Result<PackageFileIndex<'a>, DownloadError>>, DownloadError> {
+        // A worker that died is reported through its exit status once its stdout is closed.
+        if let Err(e) = serde_json::to_writer(&mut self.stdin, request)
+            .map_err(io::Error::from)
+            .and_then(|_| writeln!(self.stdin))
+            .and_then(|_| self.stdin.flush())
+        {
+            event!(Level::WARN, "Error sending request to worker: {e}");
+        }
+
+        let mut files = HashMap::new();
+        let mut current_node = 0;
+        let mut commit_package = None;
+        let mut line = String::new();
+        loop {
+            line.clear();
+            if self.stdout.read_line(&mut line)? == 0 {
+                return Ok(None);
+            }
+            let message: WorkerMessage = serde_json::from_str(&line)
+                .map_err(|e| DownloadError::WorkerError(format!("Invalid worker message: {e}")))?;
+            let result = match message {
+                WorkerMessage::File { len } => {
+                    let mut data = vec![0; len];
+                    self.stdout.read_exact(&mut data)?;
+                    current_node += 1;
+                    files.insert(current_node, data);
+                    continue;
+                }
+                WorkerMessage::Commit { paths_to_nodes } => {
+                    let mut output = output.lock().unwrap();
+                    let mut nodes = vec![];
+                    for (node, path) in paths_to_nodes {
+                        let data = files.remove(&node).unwrap_or_default();
+                        match output.add_file(data) {
+                            Ok(node) => nodes.push((node, path)),
+                            Err(e) => {
+                                let nodes = nodes.into_iter().map(|(node, _)| node).collect();
+                                output.discard(package, nodes)?;
+                                return Err(e.into());
+                            }
+                        }
+                    }
+                    commit_package = output.flush_commit(package, nodes)?;
+                    continue;
+                }
+                WorkerMessage::Index {
+                    items,
+                    truncated,
+                    metadata,
+                    analysis,
+                } => {
+                    let mut index = PackageFileIndex::new(
+                        package,
+                        items,
+                        truncated,
+                        metadata.map(|m| *m),
+                        analysis,
+                    );
+                    index.commit_package = commit_package;
+                    Ok(index)
+                }
+                WorkerMessage::Missing => Err(DownloadError::Missing),
+                WorkerMessage::SkippedFormat => Err(DownloadError::SkippedFormat),
+                WorkerMessage::Error(e) => Err(DownloadError::WorkerError(e)),
+            };
+            return Ok(Some(result));
+        }
+    }
+
+    fn exit_reason(&mut self) -> String {
+        match self.child.wait() {
+            Ok(status) => match status.signal() {
+                Some(signal) => format!("Worker {} ({signal})", describe_signal(signal)),
+                None => format!("Worker exited with {status}"),
+            },
+            Err(e) => format!("Error waiting for worker: {e}"),
+        }
+    }
+}
+
+impl Drop for Worker {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+/// Extracts packages in separate processes running under the given resource limits, so that
+/// running out of memory, spinning in a decompressor or overflowing the stack only fails that
+/// package. Workers are reused for later packages, so that each one only starts up and connects
+/// to PyPI once, and replaced when they die.
+pub struct WorkerPool {
+    limits: WorkerLimits,
+    idle: Mutex<Vec<Worker>>,
+}
+
+impl WorkerPool {
+    pub fn new(limits: WorkerLimits) -> Self {
+        WorkerPool {
+            limits,
+            idle: Mutex::new(vec![]),
+        }
+    }
+
+    pub fn download_package<'a, S: OutputSink>(
+        &self,
+        package: &'a RepositoryPackage,
+        output: &Mutex<S>,
+        options: ExtractOptions,
+    ) -> Result<PackageFileIndex<'a>, DownloadError> {
+        let idle = self.idle.lock().unwrap().pop();
+        let mut worker = match idle {
+            Some(worker) => worker,
+            None => Worker::spawn(self.limits)?,
+        };
+        let request = WorkerRequest {
+            package: package.clone(),
+            options,
+            cpu_seconds: self.limits.cpu_seconds,
+        };
+        match worker.extract(&request, package, output)? {
+            Some(result) => {
+                self.idle.lock().unwrap().push(worker);
+                result
+            }
+            None => Err(DownloadError::WorkerError(worker.exit_reason())),
+        }
+    }
+}